@@ -0,0 +1,151 @@
+// Copyright 2016 Bruno Medeiros
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::sync::Mutex;
+
+use jsonrpc_common::Id;
+
+/* -----------------  IdGenerator  ----------------- */
+
+/// Generates the ids of the requests sent by an Endpoint.
+/// Ids must be unique among the requests pending in an Endpoint.
+pub trait IdGenerator : Send + Sync {
+    fn next_id(&self) -> Id;
+}
+
+/// Generates sequential `Id::Number` ids, starting at 1. This is the default generator.
+pub struct CounterIdGenerator {
+    counter : Mutex<u64>,
+}
+
+impl CounterIdGenerator {
+    pub fn new() -> CounterIdGenerator {
+        CounterIdGenerator { counter : Mutex::new(0) }
+    }
+
+    /// The last id number generated, or 0 if none.
+    pub fn current(&self) -> u64 {
+        *self.counter.lock().unwrap()
+    }
+
+    /// Generate the next id number.
+    pub fn next_number(&self) -> u64 {
+        let mut counter = self.counter.lock().unwrap();
+        *counter += 1;
+        *counter
+    }
+}
+
+impl Default for CounterIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for CounterIdGenerator {
+    fn next_id(&self) -> Id {
+        Id::Number(self.next_number())
+    }
+}
+
+/// Generates sequential `Id::String` ids, of the form `<prefix><counter>`.
+/// Useful to keep ids of several logical clients distinct, when multiplexed onto one connection.
+pub struct PrefixIdGenerator {
+    prefix : String,
+    counter : CounterIdGenerator,
+}
+
+impl PrefixIdGenerator {
+    pub fn new(prefix: String) -> PrefixIdGenerator {
+        PrefixIdGenerator { prefix : prefix, counter : CounterIdGenerator::new() }
+    }
+}
+
+impl IdGenerator for PrefixIdGenerator {
+    fn next_id(&self) -> Id {
+        Id::String(format!("{}{}", self.prefix, self.counter.next_number()))
+    }
+}
+
+/// Generates random `Id::String` ids, formatted as version 4 UUIDs.
+///
+/// Note: randomness is seeded from the standard library hash keys,
+/// which is fine for uniqueness, but not meant to be cryptographically secure.
+pub struct RandomIdGenerator {
+    state : Mutex<u64>,
+}
+
+impl RandomIdGenerator {
+    pub fn new() -> RandomIdGenerator {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(&hasher as *const _ as usize);
+        // xorshift state must not be zero
+        RandomIdGenerator { state : Mutex::new(hasher.finish() | 1) }
+    }
+
+    fn next_u64(&self) -> u64 {
+        // xorshift64*
+        let mut state = self.state.lock().unwrap();
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+impl Default for RandomIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&self) -> Id {
+        let high = self.next_u64();
+        let low = self.next_u64();
+
+        Id::String(format!("{:08x}-{:04x}-4{:03x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0x0fff,
+            ((low >> 48) & 0x3fff) | 0x8000,
+            low & 0xffff_ffff_ffff,
+        ))
+    }
+}
+
+
+#[test]
+fn test_IdGenerator() {
+    use std::collections::HashSet;
+
+    let generator = CounterIdGenerator::new();
+    assert_eq!(generator.current(), 0);
+    assert_eq!(generator.next_id(), Id::Number(1));
+    assert_eq!(generator.next_id(), Id::Number(2));
+    assert_eq!(generator.current(), 2);
+
+    let generator = PrefixIdGenerator::new("client1-".into());
+    assert_eq!(generator.next_id(), Id::String("client1-1".into()));
+    assert_eq!(generator.next_id(), Id::String("client1-2".into()));
+
+    let generator = RandomIdGenerator::new();
+    let mut ids = HashSet::new();
+    for _ in 0..1000 {
+        let id = generator.next_id();
+        if let Id::String(ref string) = id {
+            assert_eq!(string.len(), 36);
+            assert_eq!(&string[14..15], "4");
+        } else {
+            panic!("Expected Id::String");
+        }
+        assert!(ids.insert(id));
+    }
+}
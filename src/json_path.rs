@@ -0,0 +1,484 @@
+// Copyright 2016 Bruno Medeiros
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/*!
+Deserialization of JSON values that keeps track of where in the value an error occurred.
+
+Locations are written as paths of member names and array indexes, for example `params.range.start.line`
+or `params.items[2].name`. The empty path is the root value.
+*/
+
+use std::error;
+use std::fmt;
+use std::vec;
+
+use serde;
+use serde::de;
+
+use serde_json::Value;
+use serde_json::value::MapIntoIter;
+
+/* -----------------  Paths  ----------------- */
+
+/// The path of member `key` of the value at `path`.
+pub fn member_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// The path of element `index` of the array at `path`.
+pub fn index_path(path: &str, index: usize) -> String {
+    format!("{}[{}]", path, index)
+}
+
+/// Append the relative path `sub_path` to `path`.
+fn join_path(path: &str, sub_path: &str) -> String {
+    if sub_path.is_empty() {
+        path.to_string()
+    } else if sub_path.starts_with('[') {
+        format!("{}{}", path, sub_path)
+    } else {
+        member_path(path, sub_path)
+    }
+}
+
+/* -----------------  PathError  ----------------- */
+
+/// A deserialization error, together with the location of the value that caused it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PathError {
+    /// The path of the offending value.
+    pub path : String,
+    pub message : String,
+    /// Whether `path` is complete, or still relative to the value being deserialized.
+    located : bool,
+}
+
+impl PathError {
+    pub fn new(path: String, message: String) -> PathError {
+        PathError { path : path, message : message, located : true }
+    }
+
+    fn relative(sub_path: String, message: String) -> PathError {
+        PathError { path : sub_path, message : message, located : false }
+    }
+
+    /// Resolve the location of this error, if not yet known, as being relative to `path`.
+    fn locate(mut self, path: &str) -> PathError {
+        if !self.located {
+            self.path = join_path(path, &self.path);
+            self.located = true;
+        }
+        self
+    }
+
+    /// A JSON description of this error: an object with `path` and `message` members.
+    pub fn to_value(&self) -> Value {
+        let mut object = ::json_util::new_object();
+        object.insert("path".to_string(), Value::String(self.path.clone()));
+        object.insert("message".to_string(), Value::String(self.message.clone()));
+        Value::Object(object)
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "`{}`: {}", self.path, self.message)
+    }
+}
+
+impl error::Error for PathError {
+}
+
+impl de::Error for PathError {
+    fn custom<T: Into<String>>(message: T) -> PathError {
+        PathError::relative(String::new(), message.into())
+    }
+
+    fn end_of_stream() -> PathError {
+        PathError::relative(String::new(), "Unexpected end of value.".to_string())
+    }
+
+    fn missing_field(field: &'static str) -> PathError {
+        PathError::relative(field.to_string(), "Property is missing.".to_string())
+    }
+}
+
+/* -----------------  PathDeserializer  ----------------- */
+
+/// Deserialize a `T` from given value, reporting errors with paths starting at `root_path`.
+pub fn from_value_at<T>(value: Value, root_path: &str) -> Result<T, PathError>
+    where T: serde::Deserialize
+{
+    T::deserialize(&mut PathDeserializer::new(value, root_path.to_string()))
+}
+
+/// A Deserializer of a JSON value, that locates the errors of the value it's deserializing.
+pub struct PathDeserializer {
+    value : Option<Value>,
+    path : String,
+}
+
+impl PathDeserializer {
+    pub fn new(value: Value, path: String) -> PathDeserializer {
+        PathDeserializer { value : Some(value), path : path }
+    }
+
+    fn take_value(&mut self) -> Result<Value, PathError> {
+        self.value.take().ok_or_else(de::Error::end_of_stream)
+    }
+
+    fn do_deserialize<V>(&mut self, mut visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        match try!(self.take_value()) {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(value) => visitor.visit_bool(value),
+            Value::I64(value) => visitor.visit_i64(value),
+            Value::U64(value) => visitor.visit_u64(value),
+            Value::F64(value) => visitor.visit_f64(value),
+            Value::String(value) => visitor.visit_string(value),
+            Value::Array(array) => {
+                let len = array.len();
+                visitor.visit_seq(PathSeqVisitor {
+                    iter : array.into_iter(), path : &self.path, index : 0, len : len
+                })
+            }
+            Value::Object(object) => {
+                let len = object.len();
+                visitor.visit_map(PathMapVisitor {
+                    iter : object.into_iter(), path : &self.path, value : None, len : len
+                })
+            }
+        }
+    }
+
+    fn do_deserialize_enum<V>(&mut self, mut visitor: V) -> Result<V::Value, PathError>
+        where V: de::EnumVisitor,
+    {
+        // Same encoding as serde_json: a variant name, or an object with a single variant member
+        let (variant, value) = match try!(self.take_value()) {
+            Value::Object(object) => {
+                let mut iter = object.into_iter();
+                let (variant, value) = match iter.next() {
+                    Some(entry) => entry,
+                    None => return Err(de::Error::invalid_type(de::Type::VariantName)),
+                };
+                if iter.next().is_some() {
+                    return Err(de::Error::invalid_type(de::Type::Map));
+                }
+                (variant, Some(value))
+            }
+            Value::String(variant) => (variant, None),
+            _ => return Err(de::Error::invalid_type(de::Type::Enum)),
+        };
+
+        let value_path = member_path(&self.path, &variant);
+        visitor.visit(PathVariantVisitor { variant : Some(variant), value : value, path : value_path })
+    }
+}
+
+impl de::Deserializer for PathDeserializer {
+    type Error = PathError;
+
+    fn deserialize<V>(&mut self, visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        let result = self.do_deserialize(visitor);
+        result.map_err(|error| error.locate(&self.path))
+    }
+
+    fn deserialize_option<V>(&mut self, mut visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        let result = match self.value {
+            Some(Value::Null) => visitor.visit_none(),
+            Some(_) => visitor.visit_some(self),
+            None => Err(de::Error::end_of_stream()),
+        };
+        result.map_err(|error| error.locate(&self.path))
+    }
+
+    fn deserialize_enum<V>(&mut self, _name: &str, _variants: &'static [&'static str], visitor: V)
+        -> Result<V::Value, PathError>
+        where V: de::EnumVisitor,
+    {
+        let result = self.do_deserialize_enum(visitor);
+        result.map_err(|error| error.locate(&self.path))
+    }
+
+    fn deserialize_newtype_struct<V>(&mut self, _name: &'static str, mut visitor: V)
+        -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        let result = visitor.visit_newtype_struct(self);
+        result.map_err(|error| error.locate(&self.path))
+    }
+
+    forward_to_deserialize! {
+        bool usize u8 u16 u32 u64 isize i8 i16 i32 i64 f32 f64 char str string
+        unit seq seq_fixed_size bytes map unit_struct tuple_struct struct
+        struct_field tuple ignored_any
+    }
+}
+
+struct PathSeqVisitor<'a> {
+    iter : vec::IntoIter<Value>,
+    path : &'a str,
+    index : usize,
+    len : usize,
+}
+
+impl<'a> de::SeqVisitor for PathSeqVisitor<'a> {
+    type Error = PathError;
+
+    fn visit<T>(&mut self) -> Result<Option<T>, PathError>
+        where T: serde::Deserialize,
+    {
+        match self.iter.next() {
+            Some(value) => {
+                let path = index_path(self.path, self.index);
+                self.index += 1;
+                self.len -= 1;
+                T::deserialize(&mut PathDeserializer::new(value, path)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn end(&mut self) -> Result<(), PathError> {
+        if self.len == 0 {
+            Ok(())
+        } else {
+            Err(de::Error::invalid_length(self.len))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+}
+
+struct PathMapVisitor<'a> {
+    iter : MapIntoIter<String, Value>,
+    path : &'a str,
+    /// The path and value of the member whose key was last visited
+    value : Option<(String, Value)>,
+    len : usize,
+}
+
+impl<'a> de::MapVisitor for PathMapVisitor<'a> {
+    type Error = PathError;
+
+    fn visit_key<T>(&mut self) -> Result<Option<T>, PathError>
+        where T: serde::Deserialize,
+    {
+        match self.iter.next() {
+            Some((key, value)) => {
+                let path = member_path(self.path, &key);
+                self.len -= 1;
+                self.value = Some((path.clone(), value));
+                T::deserialize(&mut PathDeserializer::new(Value::String(key), path)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn visit_value<T>(&mut self) -> Result<T, PathError>
+        where T: serde::Deserialize,
+    {
+        let (path, value) = try!(self.value.take().ok_or_else(de::Error::end_of_stream));
+        T::deserialize(&mut PathDeserializer::new(value, path))
+    }
+
+    fn end(&mut self) -> Result<(), PathError> {
+        if self.len == 0 {
+            Ok(())
+        } else {
+            Err(de::Error::invalid_length(self.len))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.len, Some(self.len))
+    }
+
+    fn missing_field<V>(&mut self, field: &'static str) -> Result<V, PathError>
+        where V: serde::Deserialize,
+    {
+        // A missing field is only valid for an Option
+        V::deserialize(&mut MissingFieldDeserializer { path : member_path(self.path, field) })
+    }
+}
+
+struct MissingFieldDeserializer {
+    path : String,
+}
+
+impl de::Deserializer for MissingFieldDeserializer {
+    type Error = PathError;
+
+    fn deserialize<V>(&mut self, _visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        Err(PathError::new(self.path.clone(), "Property is missing.".to_string()))
+    }
+
+    fn deserialize_option<V>(&mut self, mut visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        visitor.visit_none()
+    }
+
+    forward_to_deserialize! {
+        bool usize u8 u16 u32 u64 isize i8 i16 i32 i64 f32 f64 char str string
+        unit seq seq_fixed_size bytes map unit_struct newtype_struct tuple_struct struct
+        struct_field tuple enum ignored_any
+    }
+}
+
+struct PathVariantVisitor {
+    variant : Option<String>,
+    value : Option<Value>,
+    /// The path of the variant's value
+    path : String,
+}
+
+impl PathVariantVisitor {
+    fn take_value(&mut self) -> Result<PathDeserializer, PathError> {
+        match self.value.take() {
+            Some(value) => Ok(PathDeserializer::new(value, self.path.clone())),
+            None => Err(PathError::new(self.path.clone(), "Property is missing.".to_string())),
+        }
+    }
+}
+
+impl de::VariantVisitor for PathVariantVisitor {
+    type Error = PathError;
+
+    fn visit_variant<V>(&mut self) -> Result<V, PathError>
+        where V: serde::Deserialize,
+    {
+        let variant = try!(self.variant.take().ok_or_else(de::Error::end_of_stream));
+        V::deserialize(&mut PathDeserializer::new(Value::String(variant), self.path.clone()))
+    }
+
+    fn visit_unit(&mut self) -> Result<(), PathError> {
+        match self.value.take() {
+            Some(value) => serde::Deserialize::deserialize(&mut PathDeserializer::new(value, self.path.clone())),
+            None => Ok(()),
+        }
+    }
+
+    fn visit_newtype<T>(&mut self) -> Result<T, PathError>
+        where T: serde::Deserialize,
+    {
+        T::deserialize(&mut try!(self.take_value()))
+    }
+
+    fn visit_tuple<V>(&mut self, _len: usize, visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        let mut deserializer = try!(self.take_value());
+        match deserializer.value {
+            Some(Value::Array(_)) => de::Deserializer::deserialize(&mut deserializer, visitor),
+            _ => Err(PathError::new(self.path.clone(), "Value is not an Array.".to_string())),
+        }
+    }
+
+    fn visit_struct<V>(&mut self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, PathError>
+        where V: de::Visitor,
+    {
+        let mut deserializer = try!(self.take_value());
+        match deserializer.value {
+            Some(Value::Object(_)) => de::Deserializer::deserialize(&mut deserializer, visitor),
+            _ => Err(PathError::new(self.path.clone(), "Value is not an Object.".to_string())),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use json_util::test_util::*;
+    use serde;
+    use serde::de;
+    use serde_json::Value;
+
+    #[derive(Debug, PartialEq)]
+    struct Position { line : u32, character : Option<u32> }
+
+    // The tests don't use serde_derive, so implement Deserialize as it would be derived
+    impl serde::Deserialize for Position {
+        fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+            where DE: serde::Deserializer
+        {
+            struct PositionVisitor;
+
+            impl de::Visitor for PositionVisitor {
+                type Value = Position;
+
+                fn visit_map<V>(&mut self, mut visitor: V) -> Result<Position, V::Error>
+                    where V: de::MapVisitor
+                {
+                    let mut line = None;
+                    let mut character = None;
+                    while let Some(key) = try!(visitor.visit_key::<String>()) {
+                        match key.as_str() {
+                            "line" => line = Some(try!(visitor.visit_value())),
+                            "character" => character = Some(try!(visitor.visit_value())),
+                            _ => { try!(visitor.visit_value::<Value>()); }
+                        }
+                    }
+                    let line = match line { Some(line) => line, None => try!(visitor.missing_field("line")) };
+                    let character = match character {
+                        Some(character) => character, None => try!(visitor.missing_field("character"))
+                    };
+                    try!(visitor.end());
+                    Ok(Position { line : line, character : character })
+                }
+            }
+
+            deserializer.deserialize_struct("Position", &["line", "character"], PositionVisitor)
+        }
+    }
+
+    fn error_at<T: serde::Deserialize>(json: &str) -> PathError {
+        from_value_at::<T>(from_json(json), "params").err().unwrap()
+    }
+
+    #[test]
+    fn test_from_value_at() {
+        let position : Position = from_value_at(from_json(r#"{"line":1}"#), "params").unwrap();
+        assert_eq!(position, Position { line : 1, character : None });
+        let positions : Vec<Position> =
+            from_value_at(from_json(r#"[{"line":1,"character":2}]"#), "params").unwrap();
+        assert_eq!(positions, vec![Position { line : 1, character : Some(2) }]);
+
+        assert_eq!(error_at::<Position>(r#"{}"#).path, "params.line");
+        assert_eq!(error_at::<Position>(r#"{}"#).message, "Property is missing.");
+        assert_eq!(error_at::<Position>(r#"{"line":"one"}"#).path, "params.line");
+        assert_eq!(error_at::<Position>(r#"{"line":1,"character":true}"#).path, "params.character");
+        assert_eq!(error_at::<Position>(r#"[]"#).path, "params");
+
+        assert_eq!(error_at::<Vec<Position>>(r#"[{"line":1}, {"line":null}]"#).path, "params[1].line");
+        assert_eq!(error_at::<Vec<Vec<u32>>>(r#"[[], [1, -1]]"#).path, "params[1][1]");
+
+        let error = error_at::<Vec<Position>>(r#"[{}]"#);
+        assert_eq!(format!("{}", error), "`params[0].line`: Property is missing.");
+        assert_eq!(to_json(&error.to_value()), r#"{"message":"Property is missing.","path":"params[0].line"}"#);
+
+        let error = from_value_at::<u32>(from_json(r#""x""#), "").err().unwrap();
+        assert_eq!(error.path, "");
+    }
+
+}
@@ -0,0 +1,518 @@
+// Copyright 2016 Bruno Medeiros
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+
+pub use serde_json::Value;
+
+use json_util::*;
+use json_path::{PathError, member_path, index_path};
+
+/* -----------------  JsonSchema  ----------------- */
+
+/// A type whose serde JSON representation can be described by a JSON Schema.
+///
+/// Implemented for the common std types. For structs, string enums, and newtypes,
+/// use the `json_schema_struct!`, `json_schema_enum!`, and `json_schema_newtype!` macros.
+pub trait JsonSchema {
+    /// The JSON Schema of this type.
+    fn json_schema() -> Value;
+
+    /// Whether this type can be omitted, when a member of an object.
+    /// Such members are not listed as `required`.
+    fn is_optional() -> bool {
+        false
+    }
+}
+
+pub fn type_schema(type_name: &str) -> Value {
+    let mut schema = new_object();
+    schema.insert("type".into(), Value::String(type_name.into()));
+    Value::Object(schema)
+}
+
+/// The schema of an object with given properties: tuples of name, schema, and whether it is optional.
+pub fn object_schema(properties: Vec<(&str, Value, bool)>) -> Value {
+    let required = properties.iter()
+        .filter(|&&(_, _, is_optional)| !is_optional)
+        .map(|&(name, _, _)| Value::String(name.into()))
+        .collect();
+    let properties = properties.into_iter()
+        .map(|(name, schema, _)| (name.to_string(), schema))
+        .collect();
+
+    let mut schema = new_object();
+    schema.insert("type".into(), Value::String("object".into()));
+    schema.insert("properties".into(), Value::Object(properties));
+    schema.insert("required".into(), Value::Array(required));
+    Value::Object(schema)
+}
+
+/// The schema of a string that is one of the given values.
+pub fn string_enum_schema(values: &[&str]) -> Value {
+    let mut schema = new_object();
+    schema.insert("type".into(), Value::String("string".into()));
+    schema.insert("enum".into(), Value::Array(values.iter().map(|value| Value::String(value.to_string())).collect()));
+    Value::Object(schema)
+}
+
+/// Whether given schema only accepts `null`. Such params are the same as no params.
+pub fn is_null_schema(schema: &Value) -> bool {
+    schema.find("type").and_then(Value::as_str) == Some("null")
+}
+
+/* -----------------  std impls  ----------------- */
+
+macro_rules! impl_type_schema {
+    ($type_name:expr, $($type:ty),*) => {
+        $(
+            impl JsonSchema for $type {
+                fn json_schema() -> Value {
+                    type_schema($type_name)
+                }
+            }
+        )*
+    }
+}
+
+impl_type_schema!("boolean", bool);
+impl_type_schema!("integer", i8, i16, i32, i64, isize);
+impl_type_schema!("number", f32, f64);
+impl_type_schema!("string", String, str, char);
+impl_type_schema!("null", ());
+
+macro_rules! impl_unsigned_schema {
+    ($($type:ty),*) => {
+        $(
+            impl JsonSchema for $type {
+                fn json_schema() -> Value {
+                    let mut schema = unwrap_object(type_schema("integer"));
+                    schema.insert("minimum".into(), Value::U64(0));
+                    Value::Object(schema)
+                }
+            }
+        )*
+    }
+}
+
+impl_unsigned_schema!(u8, u16, u32, u64, usize);
+
+impl JsonSchema for Value {
+    fn json_schema() -> Value {
+        // The empty schema accepts any value
+        Value::Object(new_object())
+    }
+}
+
+impl<'a, T : JsonSchema + ?Sized> JsonSchema for &'a T {
+    fn json_schema() -> Value {
+        T::json_schema()
+    }
+
+    fn is_optional() -> bool {
+        T::is_optional()
+    }
+}
+
+impl<T : JsonSchema + ?Sized> JsonSchema for Box<T> {
+    fn json_schema() -> Value {
+        T::json_schema()
+    }
+
+    fn is_optional() -> bool {
+        T::is_optional()
+    }
+}
+
+impl<T : JsonSchema> JsonSchema for Option<T> {
+    fn json_schema() -> Value {
+        let mut schema = new_object();
+        schema.insert("anyOf".into(), Value::Array(vec![T::json_schema(), type_schema("null")]));
+        Value::Object(schema)
+    }
+
+    fn is_optional() -> bool {
+        true
+    }
+}
+
+fn array_schema(items: Value) -> Value {
+    let mut schema = unwrap_object(type_schema("array"));
+    schema.insert("items".into(), items);
+    Value::Object(schema)
+}
+
+impl<T : JsonSchema> JsonSchema for Vec<T> {
+    fn json_schema() -> Value {
+        array_schema(T::json_schema())
+    }
+}
+
+impl<T : JsonSchema> JsonSchema for [T] {
+    fn json_schema() -> Value {
+        array_schema(T::json_schema())
+    }
+}
+
+fn map_schema(values: Value) -> Value {
+    let mut schema = unwrap_object(type_schema("object"));
+    schema.insert("additionalProperties".into(), values);
+    Value::Object(schema)
+}
+
+impl<T : JsonSchema> JsonSchema for BTreeMap<String, T> {
+    fn json_schema() -> Value {
+        map_schema(T::json_schema())
+    }
+}
+
+impl<T : JsonSchema, S> JsonSchema for HashMap<String, T, S> {
+    fn json_schema() -> Value {
+        map_schema(T::json_schema())
+    }
+}
+
+macro_rules! impl_tuple_schema {
+    ($len:expr, $($type:ident),*) => {
+        impl<$($type : JsonSchema),*> JsonSchema for ($($type,)*) {
+            fn json_schema() -> Value {
+                let mut schema = unwrap_object(type_schema("array"));
+                schema.insert("items".into(), Value::Array(vec![$($type::json_schema()),*]));
+                schema.insert("minItems".into(), Value::U64($len));
+                schema.insert("maxItems".into(), Value::U64($len));
+                Value::Object(schema)
+            }
+        }
+    }
+}
+
+impl_tuple_schema!(1, A);
+impl_tuple_schema!(2, A, B);
+impl_tuple_schema!(3, A, B, C);
+impl_tuple_schema!(4, A, B, C, D);
+
+/* -----------------  Derive macros  ----------------- */
+
+/// Implement `JsonSchema` for a struct serialized as an object with given fields.
+/// Fields of an `Option` type are not required.
+///
+/// Example: `json_schema_struct!(Point { x : i32, y : i32 });`
+#[macro_export]
+macro_rules! json_schema_struct {
+    ($name:ident { $($field:ident : $field_type:ty),* $(,)* }) => {
+        impl $crate::json_schema::JsonSchema for $name {
+            fn json_schema() -> $crate::json_schema::Value {
+                $crate::json_schema::object_schema(vec![$(
+                    (
+                        stringify!($field),
+                        <$field_type as $crate::json_schema::JsonSchema>::json_schema(),
+                        <$field_type as $crate::json_schema::JsonSchema>::is_optional(),
+                    )
+                ),*])
+            }
+        }
+    }
+}
+
+/// Implement `JsonSchema` for an enum serialized as one of the given strings.
+///
+/// Example: `json_schema_enum!(Color ["red", "green", "blue"]);`
+#[macro_export]
+macro_rules! json_schema_enum {
+    ($name:ident [ $($value:expr),* $(,)* ]) => {
+        impl $crate::json_schema::JsonSchema for $name {
+            fn json_schema() -> $crate::json_schema::Value {
+                $crate::json_schema::string_enum_schema(&[$($value),*])
+            }
+        }
+    }
+}
+
+/// Implement `JsonSchema` for a newtype serialized as the wrapped type.
+///
+/// Example: `json_schema_newtype!(Meters(f64));`
+#[macro_export]
+macro_rules! json_schema_newtype {
+    ($name:ident ( $inner_type:ty )) => {
+        impl $crate::json_schema::JsonSchema for $name {
+            fn json_schema() -> $crate::json_schema::Value {
+                <$inner_type as $crate::json_schema::JsonSchema>::json_schema()
+            }
+        }
+    }
+}
+
+/* -----------------  Validation  ----------------- */
+
+/// Validate given value against given JSON Schema, returning every violation found,
+/// located by the path of the offending value (see `json_path`).
+///
+/// Supports the keywords generated by `JsonSchema`, and a few other common ones:
+/// `type`, `enum`, `properties`, `required`, `additionalProperties`, `items`, 
+/// `minItems`, `maxItems`, `minLength`, `maxLength`, `minimum`, `maximum`, `anyOf`, and `allOf`.
+/// Other keywords are ignored.
+pub fn validate(schema: &Value, value: &Value) -> Vec<PathError> {
+    validate_with_root(schema, value, "")
+}
+
+/// Validate given value, like `validate`, with violations located relative to given root path.
+pub fn validate_with_root(schema: &Value, value: &Value, root_path: &str) -> Vec<PathError> {
+    let mut violations = vec![];
+    validate_at(schema, value, root_path, &mut violations);
+    violations
+}
+
+fn add_violation(violations: &mut Vec<PathError>, path: &str, message: String) {
+    violations.push(PathError::new(path.to_string(), message));
+}
+
+fn is_of_type(value: &Value, type_name: &str) -> bool {
+    match type_name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().map_or(false, |number| number.fract() == 0.0),
+        _ => true,
+    }
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, violations: &mut Vec<PathError>) {
+    let schema = match schema.as_object() {
+        Some(schema) => schema,
+        None => return,
+    };
+    
+    match schema.get("type") {
+        Some(&Value::String(ref type_name)) => {
+            if !is_of_type(value, type_name) {
+                add_violation(violations, path, format!("Value is not of type `{}`.", type_name));
+                return;
+            }
+        }
+        Some(&Value::Array(ref type_names)) => {
+            let type_names : Vec<&str> = type_names.iter().filter_map(Value::as_str).collect();
+            if !type_names.iter().any(|type_name| is_of_type(value, type_name)) {
+                add_violation(violations, path, format!("Value is not of type `{}`.", type_names.join("` or `")));
+                return;
+            }
+        }
+        _ => {}
+    }
+    
+    if let Some(&Value::Array(ref allowed_values)) = schema.get("enum") {
+        if !allowed_values.contains(value) {
+            add_violation(violations, path, "Value is not one of the allowed values.".into());
+        }
+    }
+    
+    if let Some(&Value::Array(ref sub_schemas)) = schema.get("anyOf") {
+        if !sub_schemas.iter().any(|sub_schema| validate(sub_schema, value).is_empty()) {
+            add_violation(violations, path, "Value does not match any of the allowed schemas.".into());
+        }
+    }
+    if let Some(&Value::Array(ref sub_schemas)) = schema.get("allOf") {
+        for sub_schema in sub_schemas {
+            validate_at(sub_schema, value, path, violations);
+        }
+    }
+    
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+            if number < minimum {
+                add_violation(violations, path, format!("Value {} is less than the minimum of {}.", value, minimum));
+            }
+        }
+        if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+            if number > maximum {
+                add_violation(violations, path, format!("Value {} is greater than the maximum of {}.", value, maximum));
+            }
+        }
+    }
+    
+    match *value {
+        Value::String(ref string) => {
+            let length = string.chars().count() as u64;
+            if let Some(min_length) = schema.get("minLength").and_then(Value::as_u64) {
+                if length < min_length {
+                    add_violation(violations, path, format!("String is shorter than {} characters.", min_length));
+                }
+            }
+            if let Some(max_length) = schema.get("maxLength").and_then(Value::as_u64) {
+                if length > max_length {
+                    add_violation(violations, path, format!("String is longer than {} characters.", max_length));
+                }
+            }
+        }
+        Value::Array(ref array) => {
+            validate_array(schema, array, path, violations);
+        }
+        Value::Object(ref object) => {
+            validate_object(schema, object, path, violations);
+        }
+        _ => {}
+    }
+}
+
+fn validate_array(schema: &JsonObject, array: &[Value], path: &str, violations: &mut Vec<PathError>) {
+    let length = array.len() as u64;
+    if let Some(min_items) = schema.get("minItems").and_then(Value::as_u64) {
+        if length < min_items {
+            add_violation(violations, path, format!("Array has fewer than {} items.", min_items));
+        }
+    }
+    if let Some(max_items) = schema.get("maxItems").and_then(Value::as_u64) {
+        if length > max_items {
+            add_violation(violations, path, format!("Array has more than {} items.", max_items));
+        }
+    }
+    
+    match schema.get("items") {
+        Some(&Value::Array(ref item_schemas)) => {
+            // Tuple validation
+            for (index, (item_schema, item)) in item_schemas.iter().zip(array).enumerate() {
+                validate_at(item_schema, item, &index_path(path, index), violations);
+            }
+        }
+        Some(item_schema) => {
+            for (index, item) in array.iter().enumerate() {
+                validate_at(item_schema, item, &index_path(path, index), violations);
+            }
+        }
+        None => {}
+    }
+}
+
+fn validate_object(schema: &JsonObject, object: &JsonObject, path: &str, violations: &mut Vec<PathError>) {
+    if let Some(&Value::Array(ref required)) = schema.get("required") {
+        for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+                add_violation(violations, path, format!("Required property `{}` is missing.", name));
+            }
+        }
+    }
+    
+    let properties = schema.get("properties").and_then(Value::as_object);
+    let additional_properties = schema.get("additionalProperties");
+    
+    for (name, property_value) in object.iter() {
+        let property_path = member_path(path, name);
+        
+        match properties.and_then(|properties| properties.get(name)) {
+            Some(property_schema) => {
+                validate_at(property_schema, property_value, &property_path, violations);
+            }
+            None => {
+                match additional_properties {
+                    Some(&Value::Bool(false)) => {
+                        add_violation(violations, &property_path, format!("Property `{}` is not allowed.", name));
+                    }
+                    Some(additional_schema) => {
+                        validate_at(additional_schema, property_value, &property_path, violations);
+                    }
+                    None => {}
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use util::tests::*;
+    use json_util::test_util::from_json;
+
+    use serde_json::Value;
+    use std::collections::HashMap;
+
+    struct Sample {
+        _name : String,
+        _count : Option<u32>,
+        _tags : Vec<(String, f64)>,
+        _color : Color,
+        _size : Size,
+        _extra : HashMap<String, Value>,
+    }
+    json_schema_struct!(Sample { name : String, count : Option<u32>, tags : Vec<(String, f64)>,
+        color : Color, size : Size, extra : HashMap<String, Value> });
+
+    enum Color { _Red, _Green }
+    json_schema_enum!(Color ["red", "green"]);
+
+    struct Size(i64);
+    json_schema_newtype!(Size(i64));
+
+    #[test]
+    fn test_JsonSchema() {
+        assert_equal(<()>::json_schema(), from_json(r#"{"type":"null"}"#));
+        assert!(is_null_schema(&<()>::json_schema()));
+        assert!(!is_null_schema(&Value::json_schema()));
+
+        assert_equal(Sample::json_schema(), from_json(r#"{
+            "type":"object",
+            "properties":{
+                "name":{"type":"string"},
+                "count":{"anyOf":[{"type":"integer","minimum":0},{"type":"null"}]},
+                "tags":{"type":"array","items":
+                    {"type":"array","items":[{"type":"string"},{"type":"number"}],"minItems":2,"maxItems":2}
+                },
+                "color":{"type":"string","enum":["red","green"]},
+                "size":{"type":"integer"},
+                "extra":{"type":"object","additionalProperties":{}}
+            },
+            "required":["name","tags","color","size","extra"]
+        }"#));
+    }
+    
+    #[test]
+    fn test_validate() {
+        let violations = |schema: &Value, json: &str| -> Vec<(String, String)> {
+            validate(schema, &from_json(json)).into_iter()
+                .map(|violation| (violation.path, violation.message)).collect()
+        };
+        let violation = |path: &str, message: &str| (path.to_string(), message.to_string());
+        let no_violations : Vec<(String, String)> = vec![];
+        
+        let schema = Sample::json_schema();
+        assert_equal(violations(&schema, 
+            r#"{"name":"n","count":null,"tags":[["a",1.5]],"color":"red","size":-2,"extra":{"e":[]}}"#), 
+            no_violations.clone());
+        assert_equal(violations(&schema, r#"[]"#), vec![violation("", "Value is not of type `object`.")]);
+        assert_equal(violations(&schema, r#"{"name":1,"count":-1,"tags":[["a","b"], []],"color":"blue"}"#), vec![
+            violation("", "Required property `size` is missing."),
+            violation("", "Required property `extra` is missing."),
+            violation("color", "Value is not one of the allowed values."),
+            violation("count", "Value does not match any of the allowed schemas."),
+            violation("name", "Value is not of type `string`."),
+            violation("tags[0][1]", "Value is not of type `number`."),
+            violation("tags[1]", "Array has fewer than 2 items."),
+        ]);
+        
+        let schema = from_json(r#"{"type":["string","null"],"minLength":2,"maxLength":3}"#);
+        assert_equal(violations(&schema, r#"null"#), no_violations.clone());
+        assert_equal(violations(&schema, r#""abcd""#), vec![violation("", "String is longer than 3 characters.")]);
+        assert_equal(violations(&schema, r#"1"#), vec![violation("", "Value is not of type `string` or `null`.")]);
+        
+        let schema = from_json(r#"{"type":"object","properties":{"a/b":{"maximum":1}},"additionalProperties":false,
+            "allOf":[{"required":["a/b"]}]}"#);
+        assert_equal(violations(&schema, r#"{"a/b":2,"c~":1}"#), vec![
+            violation("a/b", "Value 2 is greater than the maximum of 1."),
+            violation("c~", "Property `c~` is not allowed."),
+        ]);
+        assert_equal(violations(&schema, r#"{}"#), vec![violation("", "Required property `a/b` is missing.")]);
+        
+        let located = validate_with_root(&schema, &from_json(r#"{"a/b":2}"#), "params");
+        assert_equal(located, vec![PathError::new("params.a/b".into(), "Value 2 is greater than the maximum of 1.".into())]);
+    }
+
+}
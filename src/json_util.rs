@@ -10,10 +10,13 @@ use std::fmt;
 
 use serde;
 
+use serde_json;
 use serde_json::Map;
 use serde_json::Value;
 use serde_json::builder::ObjectBuilder;
 
+use json_path;
+
 pub type JsonObject = Map<String, Value>;
 
 /* ----------------- deserialize helpers ----------------- */
@@ -33,19 +36,71 @@ pub fn unwrap_object(value: Value) -> JsonObject {
     }
 }
 
+/* ----------------- serialize helpers ----------------- */
+
+/// Serialize a key-value entry of a map. 
+/// Unlike struct elements, map keys don't need to be `'static`.
+pub fn serialize_map_entry<S, V>(serializer: &mut S, state: &mut S::MapState, key: &str, value: V) 
+    -> Result<(), S::Error>
+    where S: serde::Serializer, V: serde::Serialize
+{
+    try!(serializer.serialize_map_key(state, key));
+    serializer.serialize_map_value(state, value)
+}
+
+/// The number of extension members `serialize_extensions` will serialize.
+pub fn extensions_len(extensions: &JsonObject, standard_members: &[&str]) -> usize {
+    extensions.keys().filter(|key| !standard_members.contains(&key.as_str())).count()
+}
+
+/// Serialize the given extension members into a map, skipping those that clash with a standard member.
+pub fn serialize_extensions<S>(serializer: &mut S, state: &mut S::MapState, 
+    extensions: &JsonObject, standard_members: &[&str]) -> Result<(), S::Error>
+    where S: serde::Serializer
+{
+    for (key, value) in extensions.iter() {
+        if standard_members.contains(&key.as_str()) {
+            continue;
+        }
+        try!(serialize_map_entry(serializer, state, key, value));
+    }
+    Ok(())
+}
+
 /* -----------------  ----------------- */
 
-pub trait JsonDeserializerHelper<ERR> {
+pub trait JsonDeserializerHelper<ERR> : Sized {
     
     fn new_error(&self, error_message: &str) -> ERR;
     
+    /// The path of the value being deserialized, used to locate errors. Empty for the root value.
+    fn path(&self) -> &str {
+        ""
+    }
+    
+    /// A helper for deserializing member `key` of the current value.
+    fn at_member(&self, key: &str) -> MemberHelper<Self> {
+        MemberHelper { parent : self, path : json_path::member_path(self.path(), key) }
+    }
+    
+    fn type_error(&self, value: &Value, expected_type: &str) -> ERR {
+        if self.path().is_empty() {
+            self.new_error(&format!("Value `{}` is not {}.", value, expected_type))
+        } else {
+            self.new_error(&format!("Value `{}` of property `{}` is not {}.", value, self.path(), expected_type))
+        }
+    }
+    
     fn obtain_Value(&mut self, mut json_map : &mut JsonObject, key: & str) 
         -> Result<Value, ERR>
     {
         let value = json_map.remove(key);
         match value {
             Some(value) => { Ok(value) },
-            None => { return Err(self.new_error(&format!("Property `{}` is missing.", key))) }
+            None => { 
+                let member_path = json_path::member_path(self.path(), key);
+                return Err(self.new_error(&format!("Property `{}` is missing.", member_path))) 
+            }
         }
     }
     
@@ -66,14 +121,14 @@ pub trait JsonDeserializerHelper<ERR> {
     fn as_String(&mut self, value: Value) -> Result<String, ERR> {
         match value {
             Value::String(string) => Ok(string),
-            _ => Err(self.new_error(&format!("Value `{}` is not a String.", value))),
+            _ => Err(self.type_error(&value, "a String")),
         }
     }
     
     fn as_Object(&mut self, value: Value) -> Result<JsonObject, ERR> {
         match value {
             Value::Object(map) => Ok(map),
-            _ => Err(self.new_error(&format!("Value `{}` is not an Object.", value))),
+            _ => Err(self.type_error(&value, "an Object")),
         }
     }
     
@@ -81,7 +136,7 @@ pub trait JsonDeserializerHelper<ERR> {
         match value {
             Value::I64(num) => Ok(num as u32), // FIXME: check for truncation
             Value::U64(num) => Ok(num as u32), // FIXME: check for truncation
-            _ => Err(self.new_error(&format!("Value `{}` is not an Integer.", value))),
+            _ => Err(self.type_error(&value, "an Integer")),
         }
     }
     
@@ -89,7 +144,7 @@ pub trait JsonDeserializerHelper<ERR> {
         match value {
             Value::I64(num) => Ok(num),
             Value::U64(num) => Ok(num as i64), // FIXME: check for truncation
-            _ => Err(self.new_error(&format!("Value `{}` is not an Integer.", value))),
+            _ => Err(self.type_error(&value, "an Integer")),
         }
     }
     
@@ -98,39 +153,58 @@ pub trait JsonDeserializerHelper<ERR> {
         -> Result<String, ERR> 
     {
         let value = try!(self.obtain_Value(json_map, key));
-        self.as_String(value)
+        self.at_member(key).as_String(value)
     }
     
     fn obtain_Object(&mut self, json_map : &mut JsonObject, key: &str) 
         -> Result<JsonObject, ERR> 
     {
         let value = try!(self.obtain_Value(json_map, key));
-        self.as_Object(value)
+        self.at_member(key).as_Object(value)
     }
     
     fn obtain_Object_or(&mut self, json_map : &mut JsonObject, key: &str, default: & Fn() -> JsonObject) 
         -> Result<JsonObject, ERR> 
     {
         let value = self.obtain_Value_or(json_map, key, &|| { Value::Object(default()) });
-        self.as_Object(value)
+        self.at_member(key).as_Object(value)
     }
     
     fn obtain_u32(&mut self, json_map: &mut JsonObject, key: &str) 
         -> Result<u32, ERR> 
     {
         let value = try!(self.obtain_Value(json_map, key));
-        self.as_u32(value)
+        self.at_member(key).as_u32(value)
     }
     
     fn obtain_i64(&mut self, json_map: &mut JsonObject, key: &str) 
         -> Result<i64, ERR> 
     {
         let value = try!(self.obtain_Value(json_map, key));
-        self.as_i64(value)
+        self.at_member(key).as_i64(value)
     }
 
 }
 
+/// A helper for a member of the value of another helper. Errors are created by the parent helper,
+/// but are located at the member's path.
+pub struct MemberHelper<'a, PARENT : 'a> {
+    parent : &'a PARENT,
+    path : String,
+}
+
+impl<'a, ERR, PARENT> JsonDeserializerHelper<ERR> for MemberHelper<'a, PARENT>
+    where PARENT : JsonDeserializerHelper<ERR>
+{
+    fn new_error(&self, error_message: &str) -> ERR {
+        self.parent.new_error(error_message)
+    }
+    
+    fn path(&self) -> &str {
+        &self.path
+    }
+}
+
 pub struct SerdeJsonDeserializerHelper<DE>(pub DE);
 
 impl<'a, DE : serde::Deserializer> 
@@ -157,6 +231,23 @@ pub fn new_de_error<DE_ERROR>(message: String)
     DE_ERROR::custom(message)
 }
 
+/// The message of given serde_json error, from its error code, without the location serde_json 
+/// adds when displaying it. Errors deserializing a `Value` have no meaningful location.
+pub fn json_error_message(error: &serde_json::Error) -> String {
+    match *error {
+        serde_json::Error::Syntax(ref code, _, _) => format!("{}", code),
+        serde_json::Error::Io(ref io_error) => format!("{}", io_error),
+    }
+}
+
+/// Convert an error deserializing a nested `Value` into an error of the enclosing deserializer.
+pub fn json_to_de_error<DE_ERROR>(error: serde_json::Error) 
+    -> DE_ERROR
+    where DE_ERROR: serde::Error 
+{
+    new_de_error(json_error_message(&error))
+}
+
 /* -----------------  ----------------- */
 
 #[cfg(test)]
@@ -25,12 +25,14 @@ https://github.com/RustDT/rustdt-json_rpc/blob/master/tests/example.rs
 
 #[macro_use] extern crate log;
 extern crate serde_json;
-extern crate serde;
+#[macro_use] extern crate serde;
 
 extern crate rustdt_util as util;
 pub extern crate futures;
 
 pub mod json_util;
+pub mod json_path;
+#[macro_use] pub mod json_schema;
 pub mod jsonrpc_common;
 pub mod jsonrpc_message;
 pub mod jsonrpc_request;
@@ -38,20 +40,30 @@ pub mod jsonrpc_response;
 pub mod method_types;
 pub mod service_util;
 pub mod output_agent;
+pub mod message_codec;
+pub mod id_generator;
 
 /* -----------------  ----------------- */
 
 use util::core::*;
 
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
 use std::result::Result;
 
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::Weak;
  
 use futures::Future;
 use futures::BoxFuture;
 use futures::Complete;
+use futures::Stream;
+use futures::Async;
+use futures::Poll;
+
+use serde_json::Value;
 
 use service_util::MessageReader;
 use service_util::MessageWriter;
@@ -60,26 +72,161 @@ use jsonrpc_message::*;
 use jsonrpc_request::*;
 use jsonrpc_response::*;
 use method_types::*;
+use json_util::JsonObject;
+use json_util::json_error_message;
+use message_codec::MessageCodec;
+use message_codec::JsonCodec;
+use message_codec::MessageLimits;
+use id_generator::IdGenerator;
+use id_generator::CounterIdGenerator;
 
 /* -----------------  Endpoint  ----------------- */
 
 use output_agent::OutputAgent;
 use output_agent::OutputAgentTask;
+use output_agent::SubmitTaskError;
+use output_agent::OutputFailure;
+use output_agent::TaskPriority;
+
+
+/// The error of sending a message with an Endpoint.
+#[derive(Debug)]
+pub enum EndpointError {
+    /// The Endpoint was shut down.
+    Shutdown,
+    /// The message, or its params, failed to serialize.
+    SerializationFailed(GError),
+    /// The output agent thread died, without the Endpoint being shut down.
+    WriterDied,
+    /// The output failed, for example because the peer disconnected.
+    OutputFailed(OutputFailure),
+    /// The output agent's task queue is bounded and full, and its policy is to fail.
+    QueueFull,
+}
+
+impl From<SubmitTaskError> for EndpointError {
+    fn from(error: SubmitTaskError) -> EndpointError {
+        match error {
+            SubmitTaskError::Shutdown => EndpointError::Shutdown,
+            SubmitTaskError::QueueFull => EndpointError::QueueFull,
+            SubmitTaskError::Disconnected => EndpointError::WriterDied,
+            SubmitTaskError::Failed(failure) => EndpointError::OutputFailed(failure),
+        }
+    }
+}
+
+impl fmt::Display for EndpointError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EndpointError::SerializationFailed(ref error) => write!(fmt, "Failed to serialize JSON-RPC message: {}", error),
+            EndpointError::OutputFailed(ref failure) => write!(fmt, "{}", failure),
+            EndpointError::Shutdown => fmt.write_str("Endpoint is shut down."),
+            EndpointError::WriterDied => fmt.write_str("Output agent thread died."),
+            EndpointError::QueueFull => fmt.write_str("Output agent task queue is full."),
+        }
+    }
+}
+
+impl error::Error for EndpointError {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        match *self {
+            EndpointError::SerializationFailed(ref error) => Some(&**error),
+            EndpointError::OutputFailed(ref failure) => Some(&**failure.error()),
+            _ => None,
+        }
+    }
+}
 
+/// The requests sent by an Endpoint that await a response, by id.
+pub type PendingRequests = Arc<Mutex<HashMap<Id, Complete<ResponseResult>>>>;
 
 /// A JSON-RPC endpoint that can send requests (Client role), 
 /// and send responses to requests (Server role).
 /// 
 /// This type has (mostly) handle semantics: it can be copied freely, used in multiple threads.
 ///
-/// However, someone must be responsible for requesting an explicit shutdown of the Endpoint.
-/// If this is not done, the OutputAgent will panic once the last reference is dropped.
+/// Once the last handle is dropped, shutdown of the OutputAgent is requested. 
+/// Handles obtained with `downgrade` don't keep the Endpoint alive.
+/// For a deterministic teardown, use `shutdown_and_join`, which waits for pending output to be written.
 ///
 #[derive(Clone)]
 pub struct Endpoint {
-    id_counter : Arc<Mutex<u64>>,
-    pending_requests : Arc<Mutex<HashMap<Id, Complete<ResponseResult>>>>,
+    inner : Arc<EndpointInner>,
+}
+
+/// The state shared by the handles of an Endpoint.
+struct EndpointInner {
+    pending_requests : PendingRequests,
     output_agent : Arc<Mutex<OutputAgent>>,
+    options : EndpointOptions,
+    detected_version : Mutex<Option<ProtocolVersion>>,
+}
+
+/// Configuration of an Endpoint, fixed when the Endpoint is started.
+pub struct EndpointOptions {
+    /// The codec used to encode and decode messages.
+    pub codec : Arc<MessageCodec>,
+    /// Limits applied to incoming messages.
+    pub limits : MessageLimits,
+    /// The format of outgoing messages. `format.version` is also the version expected of incoming messages.
+    pub format : MessageFormat,
+    /// If true, the protocol version is detected from the first message received,
+    /// and then used instead of `format.version`.
+    pub auto_detect_version : bool,
+    /// If true, incoming 2.0 messages are checked against every MUST rule of the spec,
+    /// and rejected with an Invalid Request error listing the violations (see `check_spec_violations`).
+    /// Outgoing messages are made conformant as well, by omitting `None` params.
+    /// Useful in tests; off by default, since some peers aren't strictly conformant.
+    pub strict_validation : bool,
+    /// The generator of ids for outgoing requests.
+    pub id_generator : Arc<IdGenerator>,
+    /// What to do with responses whose id doesn't match any pending request.
+    pub unknown_response_policy : UnknownResponsePolicy,
+    /// Invoked when handling an incoming notification fails. 
+    /// Such errors are never sent to the peer, since the spec doesn't allow replying to notifications.
+    pub notification_error_hook : NotificationErrorHook,
+    /// Methods of control messages, such as cancel or ping, which are written with high priority,
+    /// like responses. Other messages are written in order, with normal priority, 
+    /// unless sent with an explicit priority. Default is none.
+    pub control_methods : Vec<String>,
+}
+
+/// A hook receiving the method name of a notification, and the error that handling it produced.
+pub type NotificationErrorHook = Arc<Fn(&str, &RequestError) + Send + Sync>;
+
+pub fn log_notification_error(method_name: &str, error: &RequestError) {
+    error!("Error handling JSON-RPC notification `{}`: {:?}", method_name, error);
+}
+
+/// What to do when a response is received whose id doesn't match any pending request.
+/// 
+/// Note that the spec doesn't allow replying to a response, so this is never reported back 
+/// as a response.
+#[derive(Clone)]
+pub enum UnknownResponsePolicy {
+    /// Log the response and drop it. This is the default.
+    LogAndDrop,
+    /// Log the response, and invoke given callback with it.
+    Callback(Arc<Fn(&Response) + Send + Sync>),
+    /// Log the response, and report it to the peer with a notification of given method name. 
+    /// Notification params are an object with the offending `id`, and an InvalidResponse `error`.
+    NotifyPeer(String),
+}
+
+impl Default for EndpointOptions {
+    fn default() -> Self {
+        EndpointOptions { 
+            codec : Arc::new(JsonCodec), 
+            limits : MessageLimits::default(), 
+            format : MessageFormat::default(),
+            auto_detect_version : false,
+            strict_validation : false,
+            id_generator : Arc::new(CounterIdGenerator::new()),
+            unknown_response_policy : UnknownResponsePolicy::LogAndDrop,
+            notification_error_hook : Arc::new(log_notification_error),
+            control_methods : vec![],
+        }
+    }
 }
 
 impl Endpoint {
@@ -87,30 +234,155 @@ impl Endpoint {
     pub fn start_with(output_agent: OutputAgent) 
         -> Endpoint
     {
-        Endpoint {
-            id_counter : newArcMutex(0),
-            pending_requests : newArcMutex(HashMap::new()),
-            output_agent : newArcMutex(output_agent) 
+        Self::start_with_options(output_agent, EndpointOptions::default())
+    }
+    
+    /// Start an Endpoint that encodes and decodes messages with given codec.
+    pub fn start_with_codec(output_agent: OutputAgent, codec: Arc<MessageCodec>) 
+        -> Endpoint
+    {
+        Self::start_with_options(output_agent, EndpointOptions { codec : codec, .. EndpointOptions::default() })
+    }
+    
+    pub fn start_with_options(output_agent: OutputAgent, options: EndpointOptions) 
+        -> Endpoint
+    {
+        let pending_requests : PendingRequests = newArcMutex(HashMap::new());
+        
+        // Whatever fails the output, a write or a flush, fails the pending requests as well
+        let failed_requests = pending_requests.clone();
+        output_agent.failure_handle().on_failure(Box::new(move |failure: &OutputFailure| {
+            fail_pending_requests(&failed_requests, failure);
+        }));
+        
+        let inner = EndpointInner {
+            pending_requests : pending_requests,
+            output_agent : newArcMutex(output_agent),
+            options : options,
+            detected_version : Mutex::new(None),
+        };
+        Endpoint { inner : Arc::new(inner) }
+    }
+    
+    pub fn options(&self) -> &EndpointOptions {
+        &self.inner.options
+    }
+    
+    pub fn codec(&self) -> &Arc<MessageCodec> {
+        &self.inner.options.codec
+    }
+    
+    /// The protocol version in use: as detected, if auto-detection is enabled, or as configured otherwise.
+    pub fn protocol_version(&self) -> ProtocolVersion {
+        if self.inner.options.auto_detect_version {
+            if let Some(version) = *self.inner.detected_version.lock().unwrap() {
+                return version;
+            }
         }
+        self.inner.options.format.version
     }
     
+    /// Obtain the protocol version to parse given incoming message with.
+    /// If auto-detection is enabled, the version is detected from the first message that is an Object.
+    /// Until then, the configured version is used.
+    pub fn incoming_protocol_version(&self, message: &Value) -> ProtocolVersion {
+        if !self.inner.options.auto_detect_version {
+            return self.inner.options.format.version;
+        }
+        let mut detected_version = self.inner.detected_version.lock().unwrap();
+        if let Some(version) = *detected_version {
+            return version;
+        }
+        match detect_protocol_version(message) {
+            Some(version) => {
+                *detected_version = Some(version);
+                version
+            }
+            None => self.inner.options.format.version,
+        }
+    }
+    
+    /// The format of outgoing messages.
+    pub fn message_format(&self) -> MessageFormat {
+        MessageFormat { 
+            version : self.protocol_version(), 
+            omit_none_params : self.inner.options.format.omit_none_params || self.inner.options.strict_validation,
+        }
+    }
+    
+    /// Submit a task to write given message to the output agent.
+    /// Fails if the output agent's task queue is bounded and full.
+    /// Fails if the output agent failed, in which case the error has the message of the original failure.
+    pub fn submit_message(&self, message: Message) -> Result<(), EndpointError> {
+        let priority = self.message_priority(&message);
+        self.submit_message_with_priority(message, priority)
+    }
+    
+    /// Submit a task to write given message, with given priority rather than the default one.
+    /// Fails like `submit_message`.
+    pub fn submit_message_with_priority(&self, message: Message, priority: TaskPriority) 
+        -> Result<(), EndpointError> 
+    {
+        submit_message_write_task(&self.inner.output_agent, &self.inner.options.codec, self.message_format(), 
+            &self.inner.pending_requests, priority, message)
+    }
+    
+    /// The default priority with which given message is written: high for responses and control messages 
+    /// (see `EndpointOptions::control_methods`), normal for everything else.
+    pub fn message_priority(&self, message: &Message) -> TaskPriority {
+        match *message {
+            Message::Response(_) => TaskPriority::High,
+            Message::Request(ref request) => {
+                if self.inner.options.control_methods.iter().any(|method| *method == request.method) {
+                    TaskPriority::High
+                } else {
+                    TaskPriority::Normal
+                }
+            }
+        }
+    }
+    
+    /// Whether shutdown was requested, or the output agent failed (for example, the peer disconnected).
+    /// Note that failing doesn't request shutdown of the output agent.
     pub fn is_shutdown(& self) -> bool {
-        self.output_agent.lock().unwrap().is_shutdown()
+        let output_agent = self.inner.output_agent.lock().unwrap();
+        output_agent.is_shutdown() || output_agent.is_failed()
+    }
+    
+    /// The failure of the output agent, if it failed.
+    pub fn output_failure(&self) -> Option<OutputFailure> {
+        self.inner.output_agent.lock().unwrap().failure()
     }
     
     pub fn request_shutdown(&self) {
-        self.output_agent.lock().unwrap().request_shutdown();
+        self.inner.output_agent.lock().unwrap().request_shutdown();
     }
     
     pub fn shutdown_and_join(&self) {
-        self.output_agent.lock().unwrap().shutdown_and_join();
+        self.inner.output_agent.lock().unwrap().shutdown_and_join();
     }
     
     pub fn next_id(&self) -> Id {
-           let id_num : &mut u64 = &mut *self.id_counter.lock().unwrap();
-        *id_num += 1;
-        Id::Number(*id_num)
+        self.inner.options.id_generator.next_id()
+    }
+    
+    /// Create a handle that doesn't keep this Endpoint alive.
+    pub fn downgrade(&self) -> WeakEndpoint {
+        WeakEndpoint(Arc::downgrade(&self.inner))
+    }
+}
+
+/// A handle to an Endpoint that doesn't keep it alive. See `Endpoint::downgrade`.
+#[derive(Clone)]
+pub struct WeakEndpoint(Weak<EndpointInner>);
+
+impl WeakEndpoint {
+    
+    /// Obtain the Endpoint, unless all of its handles were dropped.
+    pub fn upgrade(&self) -> Option<Endpoint> {
+        self.0.upgrade().map(|inner| Endpoint { inner : inner })
     }
+    
 }
 
 /// Combine an Endpoint with a request handler, 
@@ -146,15 +418,18 @@ impl EndpointHandler {
         EndpointHandler { endpoint : endpoint, request_handler: request_handler }
     }
     
-    /// Run a message read loop with given message reader.
-    /// Loop will be terminated only when there is an error reading a message.
+    /// Run a message read loop with given message reader. The Endpoint's message limits are applied 
+    /// to the reader (see `MessageReader::apply_limits`).
+    /// Loop will be terminated when there is an error reading a message, when the Endpoint is shutdown,
+    /// or when its output fails (in which case shutdown is requested, and the failure returned).
     pub fn run_message_read_loop<MSG_READER : ?Sized>(mut self, input: &mut MSG_READER) 
         -> GResult<()>
     where
         MSG_READER : MessageReader
     {
+        input.apply_limits(&self.endpoint.options().limits);
         loop {
-            let message = match input.read_next() {
+            let message = match input.read_next_bytes() {
                 Ok(ok) => { ok } 
                 Err(error) => { 
                     self.endpoint.request_shutdown();
@@ -162,18 +437,49 @@ impl EndpointHandler {
                 }
             };
             
-            self.handle_incoming_message(&message);
+            self.handle_incoming_message_bytes(&message);
             
-            if self.endpoint.is_shutdown() {
-                return Ok(())
+            if let Some(result) = self.read_loop_end() {
+                return result;
             }
         }
     }
     
+    /// The future equivalent of `run_message_read_loop`, reading messages from given stream.
+    /// The future completes when the stream fails or ends (shutdown is requested in both cases), 
+    /// when the Endpoint is shutdown, or when its output fails.
+    /// The stream is polled like any other, so it must not block: for example a channel, or a non-blocking 
+    /// transport. To read with a blocking MessageReader, use `run_message_read_loop` in a dedicated thread.
+    pub fn read_message_stream<STREAM>(self, input: STREAM) -> MessageReadLoop<STREAM> 
+    where 
+        STREAM : Stream<Item=String>,
+        STREAM::Error : Into<GError>,
+    {
+        MessageReadLoop { endpoint_handler : self, input : input }
+    }
+    
+    /// Check if a read loop should end, after handling a message: if the output failed
+    /// (requesting shutdown), or if the Endpoint is shutdown.
+    fn read_loop_end(&self) -> Option<GResult<()>> {
+        if let Some(failure) = self.endpoint.output_failure() {
+            self.endpoint.request_shutdown();
+            return Some(Err(new(failure)));
+        }
+        if self.endpoint.is_shutdown() {
+            return Some(Ok(()));
+        }
+        None
+    }
+    
     /// Handle an incoming message
     pub fn handle_incoming_message(&mut self, message_json: &str) {
+        self.handle_incoming_message_bytes(message_json.as_bytes())
+    }
+    
+    /// Handle an incoming message, decoding it with the endpoint's codec
+    pub fn handle_incoming_message_bytes(&mut self, message_bytes: &[u8]) {
         
-        let message = serde_json::from_str::<Message>(message_json);
+        let message = self.decode_message(message_bytes);
          
         match message {
             Ok(message) => {
@@ -182,32 +488,159 @@ impl EndpointHandler {
                 	Message::Response(response) => self.endpoint.handle_incoming_response(response),
                 }
             } 
+            Err(DecodeFailure::Reply(error_response)) => {
+                if let Err(error) = self.endpoint.submit_message(error_response.into()) {
+                    error!("Failed to submit JSON-RPC error response: {}", error);
+                }
+            }
+            Err(DecodeFailure::InvalidResponse(id, error)) => {
+                self.endpoint.handle_invalid_response(id, error);
+            }
+        }
+    }
+    
+    /// Decode a message, checking it against the endpoint's message limits.
+    /// 
+    /// From the spec, if the message is not well-formed, the error is a Parse error (-32700),
+    /// otherwise it is an Invalid Request (-32600), with the message id if it can still be recovered.
+    /// The error is replied only to a request, or to a message that isn't even recognizable 
+    /// as a request or response (with a null id). Nothing may be replied to a response. 
+    fn decode_message(&self, message_bytes: &[u8]) -> Result<Message, DecodeFailure> {
+        let options = self.endpoint.options();
+        
+        if let Err(error) = options.limits.check_message_size(message_bytes.len()) {
+            return Err(DecodeFailure::Reply(Response::new_error(Id::Null, error_JSON_RPC_InvalidRequest(error))));
+        }
+        
+        let value = match options.codec.decode_value(message_bytes) {
+            Ok(value) => value,
             Err(error) => {
-                let error = error_JSON_RPC_InvalidRequest(error);
-                submit_error_write_task(&self.endpoint.output_agent, error); 
+                return Err(DecodeFailure::Reply(Response::new_error(Id::Null, error_JSON_RPC_ParseError(error))));
+            }
+        };
+        
+        let kind = classify_message(&value);
+        let id = recover_message_id(&value);
+        let invalid = |message: String, data: Option<Value>| {
+            DecodeFailure::new_invalid(kind, id.clone(), message, data)
+        };
+        
+        if let Err(error) = options.limits.check_value(&value) {
+            return Err(invalid(error, None));
+        }
+        
+        let version = self.endpoint.incoming_protocol_version(&value);
+        if options.strict_validation && version == ProtocolVersion::V2 {
+            let violations = check_spec_violations(&value);
+            if !violations.is_empty() {
+                let message = violations.join(" ");
+                let data = Value::Array(violations.into_iter().map(Value::String).collect());
+                return Err(invalid(message, Some(data)));
             }
         }
+        
+        Message::from_value(value, version)
+            .map_err(|error| invalid(json_error_message(&error), None))
     }
 
     /// Handle a well-formed incoming JsonRpc request object
     pub fn handle_incoming_request(&mut self, request: Request) {
-        let output_agent = self.endpoint.output_agent.clone();
+        let endpoint = self.endpoint.clone();
+        let method_name = request.method.clone();
         
         let on_response = new(move |response: Option<Response>| {
             if let Some(response) = response {
-                submit_message_write_task(&output_agent, response.into()); 
+                if let Err(error) = endpoint.submit_message(response.into()) {
+                    error!("Failed to submit JSON-RPC response: {}", error);
+                }
             } else {
-                let method_name = ""; // TODO
                 info!("JSON-RPC notification complete. {:?}", method_name);
             } 
         });
-        let completable = ResponseCompletable::new(request.id, on_response);
+        
+        let notification_error_hook = self.endpoint.options().notification_error_hook.clone();
+        let method_name = request.method.clone();
+        let on_notification_error = new(move |error: &RequestError| {
+            notification_error_hook(&method_name, error);
+        });
+        
+        let completable = ResponseCompletable::new_with_notification_error_handler(
+            request.id, on_response, on_notification_error)
+            .with_request_extensions(request.extensions);
         
         self.request_handler.handle_request(&request.method, request.params, completable); 
     }
 
 }
 
+/// The failure to decode an incoming message.
+enum DecodeFailure {
+    /// An error response to send back.
+    Reply(Response),
+    /// An invalid response, with the id it was recovered. The matching pending request is failed.
+    InvalidResponse(Id, RequestError),
+}
+
+impl DecodeFailure {
+    fn new_invalid(kind: MessageKind, id: Id, message: String, data: Option<Value>) -> DecodeFailure {
+        let with_data = |mut error: RequestError| {
+            error.data = data;
+            error
+        };
+        match kind {
+            MessageKind::Request => {
+                let error = with_data(error_JSON_RPC_InvalidRequest(message));
+                DecodeFailure::Reply(Response::new_error(id, error))
+            }
+            MessageKind::Response => {
+                DecodeFailure::InvalidResponse(id, with_data(error_JSON_RPC_InvalidResponse(message)))
+            }
+            MessageKind::Unknown => {
+                let error = with_data(error_JSON_RPC_InvalidRequest(message));
+                DecodeFailure::Reply(Response::new_error(Id::Null, error))
+            }
+        }
+    }
+}
+
+/// The future of a message read loop. See `EndpointHandler::read_message_stream`.
+pub struct MessageReadLoop<STREAM> {
+    endpoint_handler : EndpointHandler,
+    input : STREAM,
+}
+
+impl<STREAM> Future for MessageReadLoop<STREAM> 
+where 
+    STREAM : Stream<Item=String>,
+    STREAM::Error : Into<GError>,
+{
+    type Item = ();
+    type Error = GError;
+    
+    fn poll(&mut self) -> Poll<(), GError> {
+        loop {
+            let message = match self.input.poll() {
+                Ok(Async::Ready(Some(message))) => message,
+                Ok(Async::Ready(None)) => {
+                    self.endpoint_handler.endpoint.request_shutdown();
+                    return Ok(Async::Ready(()));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(error) => {
+                    self.endpoint_handler.endpoint.request_shutdown();
+                    return Err(error.into());
+                }
+            };
+            
+            self.endpoint_handler.handle_incoming_message(&message);
+            
+            if let Some(result) = self.endpoint_handler.read_loop_end() {
+                return result.map(Async::Ready);
+            }
+        }
+    }
+}
+
 /* ----------------- Response handling ----------------- */
 
 pub trait RequestHandler {
@@ -233,37 +666,73 @@ impl RequestHandler for NullRequestHandler {
 /// 
 /// On completion, the on_response callback is invoked. 
 /// Typically: this will write an appropriate JSON-RPC response to the endpoint output.
+/// 
+/// If the request is a notification, on_response is always invoked with None. 
+/// An error result is instead given to the on_notification_error callback.
+/// 
+/// The extension members of the request are available to the handler, 
+/// which can also set the extension members of the response.
 pub struct ResponseCompletable {
     completion_flag: FinishedFlag,
     id: Option<Id>,
     on_response: Box<FnMut(Option<Response>) + Send>,
+    on_notification_error: Box<FnMut(&RequestError) + Send>,
+    request_extensions: JsonObject,
+    response_extensions: JsonObject,
 }
 
 impl ResponseCompletable {
     
     pub fn new(id: Option<Id>, on_response: Box<FnMut(Option<Response>) + Send>) -> ResponseCompletable {
+        Self::new_with_notification_error_handler(id, on_response, new(|error: &RequestError| {
+            log_notification_error("", error);
+        }))
+    }
+    
+    pub fn new_with_notification_error_handler(
+        id: Option<Id>, 
+        on_response: Box<FnMut(Option<Response>) + Send>,
+        on_notification_error: Box<FnMut(&RequestError) + Send>,
+    ) -> ResponseCompletable {
         ResponseCompletable { 
-            completion_flag : FinishedFlag(false), id : id, on_response: on_response
+            completion_flag : FinishedFlag(false), id : id, on_response: on_response, 
+            on_notification_error : on_notification_error,
+            request_extensions : json_util::new_object(), response_extensions : json_util::new_object(),
         }
     }
     
+    pub fn with_request_extensions(mut self, request_extensions: JsonObject) -> ResponseCompletable {
+        self.request_extensions = request_extensions;
+        self
+    }
+    
+    /// The extension members of the request being handled.
+    pub fn request_extensions(&self) -> &JsonObject {
+        &self.request_extensions
+    }
+    
+    /// Set the extension members of the response. Ignored for notifications.
+    pub fn set_response_extensions(&mut self, response_extensions: JsonObject) {
+        self.response_extensions = response_extensions;
+    }
+    
     pub fn complete(mut self, response_result: Option<ResponseResult>) {
         self.completion_flag.finish();
         
         // From the spec: `A Notification is a Request object without an "id" member.`
-        if let Some(response_result) = response_result {
-            
-            let response =
-            if let Some(id) = self.id {
-                Response{ id : id, result_or_error : response_result }
-            } else {
-                Response::new_error(Id::Null, 
-                    error_JSON_RPC_InvalidRequest("Property `id` not provided for request."))
-            };
-            
-            (self.on_response)(Some(response));
-        } else {
-            (self.on_response)(None)
+        // and `The Server MUST NOT reply to a Notification`
+        match (self.id, response_result) {
+            (Some(id), Some(response_result)) => {
+                (self.on_response)(Some(Response{ id : id, result_or_error : response_result, 
+                    extensions : self.response_extensions }));
+            }
+            (None, Some(ResponseResult::Error(error))) => {
+                (self.on_notification_error)(&error);
+                (self.on_response)(None)
+            }
+            _ => {
+                (self.on_response)(None)
+            }
         }
     }
     
@@ -366,14 +835,14 @@ impl<
     {
         let params_value = params.into_value();
         
-        let params_result : Result<PARAMS, _> = serde_json::from_value(params_value);
+        let params_result : Result<PARAMS, _> = json_path::from_value_at(params_value, "params");
         
         match params_result {
             Ok(params) => { 
                 method_fn(params, self);
             }
             Err(error) => {
-                self.completable.complete_with_error(error_JSON_RPC_InvalidParams(error));
+                self.completable.complete_with_error(error_JSON_RPC_InvalidParams_at(&error.path, &error.message));
             }
         }
     }
@@ -383,58 +852,124 @@ impl<
     }
 }
 
-pub fn submit_message_write_task(output_agent: &Arc<Mutex<OutputAgent>>, jsonrpc_message: Message) {
+/// Submit a task to write given message to the output agent.
+/// 
+/// The message is encoded before submitting. A response that fails to encode 
+/// is replaced by an Internal Error response, so that the peer still gets a reply.
+/// 
+/// If writing fails, the output agent is put in the failed state, 
+/// and the pending requests are completed with an error. 
+pub fn submit_message_write_task(
+    output_agent: &Arc<Mutex<OutputAgent>>, codec: &Arc<MessageCodec>, format: MessageFormat, 
+    pending_requests: &PendingRequests, priority: TaskPriority, jsonrpc_message: Message
+) -> Result<(), EndpointError> {
+    let message_bytes = try!(encode_message_for_output(&**codec, &format, jsonrpc_message));
+    let pending_requests = pending_requests.clone();
+    
+    let task_submitter = {
+        let output_agent = output_agent.lock().unwrap();
+        if output_agent.is_shutdown() {
+            return Err(EndpointError::Shutdown);
+        }
+        output_agent.task_submitter()
+    };
+    // The output agent lock is released, since submitting to a full queue may block
+    let failure_handle = task_submitter.failure_handle();
     
     let write_task : OutputAgentTask = Box::new(move |mut response_handler| {
-        info!("JSON-RPC message: {:?}", jsonrpc_message);
-        
-        let response_str = serde_json::to_string(&jsonrpc_message).unwrap_or_else(|error| -> String { 
-            panic!("Failed to serialize to JSON object: {}", error);
-        });
-        
-        let write_res = response_handler.write_message(&response_str);
+        if let Some(failure) = failure_handle.failure() {
+            // Failed after this task was queued
+            fail_pending_requests(&pending_requests, &failure);
+            return;
+        }
+        let write_res = response_handler.write_message_bytes(&message_bytes);
         if let Err(error) = write_res {
-            // FIXME handle output stream write error by shutting down
             error!("Error writing JSON-RPC message: {}", error);
+            failure_handle.fail(error);
+            if let Some(failure) = failure_handle.failure() {
+                fail_pending_requests(&pending_requests, &failure);
+            }
         };
     });
     
-    let res = {
-        output_agent.lock().unwrap().try_submit_task(write_task)
-    }; 
-    // If res is error, panic here, outside of thread lock
-    res.expect("Output agent is shutdown or thread panicked!");
+    Ok(try!(task_submitter.try_submit_task_with_priority(write_task, priority)))
 }
 
-pub fn submit_error_write_task(output_agent: &Arc<Mutex<OutputAgent>>, error: RequestError) {
+fn encode_message_for_output(codec: &MessageCodec, format: &MessageFormat, message: Message) 
+    -> Result<Vec<u8>, EndpointError> 
+{
+    info!("JSON-RPC message: {:?}", message);
+    
+    let error = match codec.encode_message(&message, format) {
+        Ok(message_bytes) => return Ok(message_bytes),
+        Err(error) => error,
+    };
+    match message {
+        Message::Response(response) => {
+            error!("Failed to serialize JSON-RPC response, replying with Internal Error: {}", error);
+            let response = Response::new_error(response.id, error_JSON_RPC_InternalError());
+            codec.encode_message(&response.into(), format).map_err(EndpointError::SerializationFailed)
+        }
+        Message::Request(_) => Err(EndpointError::SerializationFailed(error)),
+    }
+}
+
+pub fn submit_error_write_task(
+    output_agent: &Arc<Mutex<OutputAgent>>, codec: &Arc<MessageCodec>, format: MessageFormat, 
+    pending_requests: &PendingRequests, error: RequestError
+) -> Result<(), EndpointError> {
     let id = Id::Null;
     let response = Response::new_error(id, error);
-    submit_message_write_task(output_agent, response.into()); 
+    submit_message_write_task(output_agent, codec, format, pending_requests, TaskPriority::High, 
+        response.into())
+}
+
+/// Complete all pending requests with an error, because of given output failure.
+fn fail_pending_requests(pending_requests: &PendingRequests, failure: &OutputFailure) {
+    let pending : Vec<_> = pending_requests.lock().unwrap().drain().collect();
+    for (_, completable) in pending {
+        completable.send(ResponseResult::Error(error_JSON_RPC_OutputFailed(&failure.message))).ok();
+    }
 }
 
 /* -----------------  Request sending  ----------------- */
 
+fn new_request<PARAMS : serde::Serialize>(id: Option<Id>, method_name: &str, params: PARAMS, extensions: JsonObject) 
+    -> Result<Request, EndpointError> 
+{
+    let params_value = serde_json::to_value(&params);
+    let params = try!(jsonrpc_request::to_jsonrpc_params(params_value)
+        .map_err(EndpointError::SerializationFailed));
+    
+    Ok(Request { id: id, method : method_name.into(), params : params, extensions : extensions })
+}
+
 pub type RequestFuture<RET, RET_ERROR> = BoxFuture<RequestResult<RET, RET_ERROR>, futures::Canceled>;
 
 
 impl Endpoint {
     
-    /// Send a (non-notification) request
+    /// Send a (non-notification) request. 
+    /// Fails if the Endpoint is shut down or its output failed, if the params fail to serialize,
+    /// or if the output agent's task queue is bounded and full, and its policy is to fail.
     pub fn send_request<
         PARAMS : serde::Serialize, 
         RET: serde::Deserialize, 
         RET_ERROR : serde::Deserialize, 
     >(&mut self, method_name: &str, params: PARAMS) 
-        -> GResult<RequestFuture<RET, RET_ERROR>> 
+        -> Result<RequestFuture<RET, RET_ERROR>, EndpointError> 
     {
         let (completable, future) = futures::oneshot::<ResponseResult>();
         let future : futures::Oneshot<ResponseResult> = future;
         
         let id = self.next_id();
         
-        self.pending_requests.lock().unwrap().insert(id.clone(), completable);
+        self.inner.pending_requests.lock().unwrap().insert(id.clone(), completable);
         
-        self.write_request(Some(id), method_name, params)?;
+        if let Err(error) = self.write_request(Some(id.clone()), method_name, params) {
+            self.inner.pending_requests.lock().unwrap().remove(&id);
+            return Err(error);
+        }
         
         let future = future.map(|response_result : ResponseResult| {
             RequestResult::<RET, RET_ERROR>::from(response_result)
@@ -444,50 +979,96 @@ impl Endpoint {
     }
     
     
-    /// Send a notification
+    /// Send a notification. Fails for the same reasons as `send_request`.
     pub fn send_notification<
         PARAMS : serde::Serialize, 
     >(&self, method_name: &str, params: PARAMS) 
-        -> GResult<()> 
+        -> Result<(), EndpointError> 
     {
         let id = None;
         self.write_request::<_>(id, method_name, params)
     }
     
+    /// Send a notification, written with given priority rather than the default one.
+    /// For example, bulk notifications such as progress reports can be sent with `TaskPriority::Low`,
+    /// so that they don't delay other messages. Fails for the same reasons as `send_request`.
+    pub fn send_notification_with_priority<
+        PARAMS : serde::Serialize, 
+    >(&self, method_name: &str, params: PARAMS, priority: TaskPriority) 
+        -> Result<(), EndpointError> 
+    {
+        let rpc_request = try!(new_request(None, method_name, params, json_util::new_object()));
+        self.submit_message_with_priority(Message::Request(rpc_request), priority)
+    }
+    
     pub fn write_request<
         PARAMS : serde::Serialize, 
     >(&self, id: Option<Id>, method_name: &str, params: PARAMS) 
-        -> GResult<()> 
+        -> Result<(), EndpointError> 
     {
-        let params_value = serde_json::to_value(&params);
-        let params = jsonrpc_request::to_jsonrpc_params(params_value)?;
-        
-        let rpc_request = Request { id: id.clone(), method : method_name.into(), params : params };
-        
-        submit_message_write_task(&self.output_agent, Message::Request(rpc_request));
-        Ok(())
+        self.write_request_with_extensions(id, method_name, params, json_util::new_object())
+    }
+    
+    /// Write a request with the given extension members (members other than the standard ones).
+    pub fn write_request_with_extensions<
+        PARAMS : serde::Serialize, 
+    >(&self, id: Option<Id>, method_name: &str, params: PARAMS, extensions: JsonObject) 
+        -> Result<(), EndpointError> 
+    {
+        let rpc_request = try!(new_request(id, method_name, params, extensions));
+        self.submit_message(Message::Request(rpc_request))
     }
     
     
     /// Handle a well-formed incoming JsonRpc request object
     pub fn handle_incoming_response(&mut self, response: Response) {
-        let id = response.id;
-        let result_or_error = response.result_or_error;
-        
-        let entry = self.pending_requests.lock().unwrap().remove(&id);
+        let entry = self.inner.pending_requests.lock().unwrap().remove(&response.id);
         
         match entry {
         	Some(entry) => { 
-        	    entry.complete(result_or_error) 
+        	    entry.complete(response.result_or_error) 
         	} 
         	None => { 
-                let id = Id::Null;
-                let error = error_JSON_RPC_InvalidResponse(format!("id `{}` not found", id));
-                submit_error_write_task(&self.output_agent, error); 
+                self.handle_unknown_response(response);
         	}
         }
     }
     
+    /// Handle a response that failed to decode, with the id it was recovered: 
+    /// the matching pending request, if any, is completed with given error.
+    pub fn handle_invalid_response(&self, id: Id, error: RequestError) {
+        warn!("Invalid JSON-RPC response with id `{}`: {}", id, error.message);
+        
+        let entry = self.inner.pending_requests.lock().unwrap().remove(&id);
+        if let Some(entry) = entry {
+            entry.send(ResponseResult::Error(error)).ok();
+        }
+    }
+    
+    fn handle_unknown_response(&self, response: Response) {
+        warn!("JSON-RPC response with unknown id `{}`: {:?}", response.id, response);
+        
+        match self.inner.options.unknown_response_policy {
+            UnknownResponsePolicy::LogAndDrop => {}
+            UnknownResponsePolicy::Callback(ref callback) => {
+                callback(&response);
+            }
+            UnknownResponsePolicy::NotifyPeer(ref method_name) => {
+                let error = error_JSON_RPC_InvalidResponse(format!("id `{}` not found", response.id));
+                
+                let mut params = json_util::new_object();
+                params.insert("id".to_string(), serde_json::to_value(&response.id));
+                params.insert("error".to_string(), serde_json::to_value(&error));
+                
+                let notification = Request { id : None, method : method_name.clone(), 
+                    params : RequestParams::Object(params), extensions : json_util::new_object() };
+                if let Err(error) = self.submit_message(notification.into()) {
+                    error!("Failed to submit unknown response notification: {}", error);
+                }
+            }
+        }
+    }
+    
 }
 
 pub mod map_request_handler;
@@ -505,6 +1086,7 @@ mod tests_ {
     use util::tests::*;
     use tests_sample_types::*;
     use map_request_handler::MapRequestHandler;
+    use json_util::new_object;
     
     use std::thread;
     
@@ -520,10 +1102,12 @@ mod tests_ {
     use json_util::JsonObject;
     use json_util::test_util::to_json;
     use service_util::WriteLineMessageWriter;
+    use service_util::LengthPrefixedMessageReader;
     
     use futures::task::Unpark;
     use futures::Async;
     use std::sync::Arc;
+    use std::sync::Mutex;
     
     
     pub fn sample_fn(params: Point) -> MethodResult<String, ()> {
@@ -586,14 +1170,15 @@ mod tests_ {
         }
         
         let mut request_handler = MapRequestHandler::new();
-        request_handler.add_request("sample_fn", Box::new(sample_fn));
-        request_handler.add_rpc_handler("async_method", Box::new(async_method));
+        request_handler.add_request("sample_fn", Box::new(sample_fn)).unwrap();
+        request_handler.add_rpc_handler("async_method", Box::new(async_method)).unwrap();
         
         // test with invalid params = "{}" 
         let request = Request::new(1, "sample_fn".to_string(), JsonObject::new());
         invoke_method(&mut request_handler, &request.method, request.params, 
             |result| 
-            check_request(result.unwrap(), ResponseResult::Error(error_JSON_RPC_InvalidParams(r#"missing field "x""#)))
+            check_request(result.unwrap(), 
+                ResponseResult::Error(error_JSON_RPC_InvalidParams_at("params.x", "Property is missing.")))
         );
         
         // test with valid params
@@ -611,10 +1196,11 @@ mod tests_ {
         
         
         // Test valid request with params = "null"
-        request_handler.add_request("no_params_method", Box::new(no_params_method));
+        request_handler.add_request("no_params_method", Box::new(no_params_method)).unwrap();
         
         let id1 = Some(Id::Number(1));
-        let request = Request { id : id1, method : "no_params_method".into(), params : RequestParams::None, };
+        let request = Request { id : id1, method : "no_params_method".into(), params : RequestParams::None, 
+            extensions : new_object() };
         invoke_method(&mut request_handler, &request.method, request.params.clone(), 
             |result| 
             assert_equal(result.unwrap(), ResponseResult::Result(
@@ -624,22 +1210,34 @@ mod tests_ {
         
         // --- Endpoint:
         let output = vec![];
-        let mut eh = EndpointHandler::create_with_writer(WriteLineMessageWriter(output), new(request_handler));
+        let output_agent = OutputAgent::start_with_provider(|| WriteLineMessageWriter(output));
+        let id_counter = Arc::new(CounterIdGenerator::new());
+        let options = EndpointOptions { id_generator : id_counter.clone(), .. test_options() };
+        let mut eh = EndpointHandler::create(Endpoint::start_with_options(output_agent, options), 
+            new(request_handler));
         
         // Test ResponseCompletable - missing id for notification method
         let completable = ResponseCompletable::new(None, new(|_| {}));
         completable.complete(None);
         
         // Test ResponseCompletable - missing id for regular method
-        let completable = ResponseCompletable::new(None, new(|_| {}));
+        let completable = ResponseCompletable::new(None, new(|response| assert!(response.is_none())));
         completable.complete(Some(ResponseResult::Result(Value::String("1020".to_string()))));
         
+        // Test ResponseCompletable - error for notification
+        let completable = ResponseCompletable::new_with_notification_error_handler(None, 
+            new(|response| assert!(response.is_none())),
+            new(|error: &RequestError| assert_equal(error, &error_JSON_RPC_MethodNotFound()))
+        );
+        completable.complete_with_error(error_JSON_RPC_MethodNotFound());
+        
         // test again using handle_request
         // TODO review this code
         let request = Request {     
             id : None,
             method : "sample_fn".into(),
             params : request.params.clone(),
+            extensions : new_object(),
         }; 
         eh.handle_incoming_request(request);
         
@@ -650,12 +1248,12 @@ mod tests_ {
         
         eh.endpoint.send_notification("async_method", params.clone()).unwrap();
         
-        assert_eq!(*eh.endpoint.id_counter.lock().unwrap(), 0);
+        assert_eq!(id_counter.current(), 0);
         
         let my_method = "sample_fn".to_string();
         let future : RequestFuture<String, ()> = eh.endpoint.send_request(&my_method, params.clone()).unwrap();
         
-        assert_eq!(*eh.endpoint.id_counter.lock().unwrap(), 1);
+        assert_eq!(id_counter.current(), 1);
         
         // Test future is not completed
         let mut spawn = futures::task::spawn(future);
@@ -675,6 +1273,766 @@ mod tests_ {
         eh.endpoint.request_shutdown();
     }
     
+    /// Options for test Endpoints: the same as the defaults, but with strict validation.
+    pub fn test_options() -> EndpointOptions {
+        EndpointOptions { strict_validation : true, .. EndpointOptions::default() }
+    }
+    
+    /// Create an OutputAgent that writes lines to the returned buffer
+    pub fn new_capturing_output_agent() -> (OutputAgent, Arc<Mutex<Vec<u8>>>) {
+        let output = newArcMutex(vec![] as Vec<u8>);
+        let output2 = output.clone();
+        
+        let agent = OutputAgent::start(move |inner_runner: output_agent::AgentInnerRunner| {
+            inner_runner.enter_agent_loop(&mut move |task: OutputAgentTask| {
+                let mut lock = output2.lock().unwrap();
+                task(&mut WriteLineMessageWriter(&mut *lock));
+            });
+        });
+        (agent, output)
+    }
+    
+    pub fn captured_output(endpoint: &Endpoint, output: Arc<Mutex<Vec<u8>>>) -> String {
+        endpoint.shutdown_and_join();
+        let output = output.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+    
+    #[test]
+    fn test_Endpoint_drop() {
+        use output_agent::AgentInnerRunner;
+        use std::sync::mpsc;
+        use std::time::Duration;
+        
+        let output = newArcMutex(vec![] as Vec<u8>);
+        let output2 = output.clone();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        
+        let output_agent = OutputAgent::start(move |inner_runner: AgentInnerRunner| {
+            inner_runner.enter_agent_loop(&mut |task: OutputAgentTask| {
+                task(&mut WriteLineMessageWriter(&mut *output2.lock().unwrap()));
+            });
+            done_tx.send(()).unwrap();
+        });
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let endpoint2 = endpoint.clone();
+        let weak_endpoint = endpoint.downgrade();
+        
+        drop(endpoint);
+        let upgraded = weak_endpoint.upgrade().expect("endpoint2 is alive");
+        upgraded.send_notification("last", ()).unwrap();
+        drop(upgraded);
+        drop(endpoint2);
+        
+        // All strong handles dropped: the output agent stops, without an explicit shutdown
+        assert!(weak_endpoint.upgrade().is_none());
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let output = output.lock().unwrap().clone();
+        assert_equal(String::from_utf8(output).unwrap(), r#"{"jsonrpc":"2.0","method":"last"}"#.to_string() + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_output_failure() {
+        use service_util::MessageWriter;
+        use std::error;
+        use std::io;
+        
+        struct DisconnectedWriter;
+        
+        impl MessageWriter for DisconnectedWriter {
+            fn write_message(&mut self, _msg: &str) -> Result<(), GError> {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "Broken pipe").into())
+            }
+        }
+        
+        let output_agent = OutputAgent::start_with_provider(|| DisconnectedWriter);
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        assert!(!endpoint.is_shutdown());
+        
+        // The request write fails, which fails the request itself
+        let future = endpoint.send_request::<_, (), ()>("first", ()).unwrap();
+        let result = future.wait().unwrap();
+        assert_equal(result, RequestResult::RequestError(error_JSON_RPC_OutputFailed("Broken pipe")));
+        
+        assert!(endpoint.is_shutdown());
+        assert_equal(endpoint.output_failure().map(|failure| failure.message), Some("Broken pipe".to_string()));
+        // The original error is kept
+        let failure = endpoint.output_failure().unwrap();
+        assert_eq!(failure.error().downcast_ref::<io::Error>().map(io::Error::kind), Some(io::ErrorKind::BrokenPipe));
+        
+        let error = endpoint.send_notification("second", ()).unwrap_err();
+        assert_equal(format!("{}", error), "Output agent failed: Broken pipe".to_string());
+        assert_equal(error::Error::source(&error).map(|cause| format!("{}", cause)), Some("Broken pipe".to_string()));
+        check_err_contains(endpoint.send_request::<_, (), ()>("third", ()).err().unwrap(), "Broken pipe");
+        assert!(endpoint.inner.pending_requests.lock().unwrap().is_empty());
+        
+        endpoint.shutdown_and_join();
+    }
+    
+    #[test]
+    fn test_Endpoint_send_errors() {
+        use output_agent::AgentInnerRunner;
+        use message_codec::*;
+        use std::sync::mpsc;
+        
+        // Send after shutdown
+        let (output_agent, _output) = new_capturing_output_agent();
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        endpoint.shutdown_and_join();
+        match endpoint.send_notification("method", ()) {
+            Err(EndpointError::Shutdown) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        match endpoint.send_request::<_, (), ()>("method", ()) {
+            Err(EndpointError::Shutdown) => {}
+            _ => panic!("Expected EndpointError::Shutdown"),
+        }
+        
+        // Writer thread died
+        let (exited_tx, exited_rx) = mpsc::channel::<()>();
+        let output_agent = OutputAgent::start(move |inner_runner: AgentInnerRunner| {
+            drop(inner_runner);
+            exited_tx.send(()).unwrap();
+        });
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        exited_rx.recv().unwrap();
+        match endpoint.send_notification("method", ()) {
+            Err(EndpointError::WriterDied) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        endpoint.shutdown_and_join();
+        
+        // Params that don't serialize as params
+        let (output_agent, output) = new_capturing_output_agent();
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        match endpoint.send_notification("method", 123) {
+            Err(error @ EndpointError::SerializationFailed(_)) => check_err_contains(error, 
+                "Failed to serialize JSON-RPC message: Property `params` not an Object, Array, or null."),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        
+        // A response that fails to serialize is replaced by an Internal Error response
+        struct NoStringResultCodec;
+        
+        impl MessageCodec for NoStringResultCodec {
+            fn encode_value(&self, value: &Value) -> GResult<Vec<u8>> {
+                if let Some(&Value::String(_)) = value.find("result") {
+                    return Err("Can't encode String results.".into());
+                }
+                JsonCodec.encode_value(value)
+            }
+            
+            fn decode_value(&self, bytes: &[u8]) -> GResult<Value> {
+                JsonCodec.decode_value(bytes)
+            }
+        }
+        assert_equal(captured_output(&endpoint, output), "".to_string());
+        
+        let (output_agent, output) = new_capturing_output_agent();
+        let options = EndpointOptions { codec : Arc::new(NoStringResultCodec), .. test_options() };
+        let endpoint = Endpoint::start_with_options(output_agent, options);
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_request("sample_fn", Box::new(sample_fn)).unwrap();
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 1, "method": "sample_fn", "params": {"x": 1, "y": 2} }"#);
+        assert_equal(captured_output(&endpoint, output), 
+            r#"{"error":{"code":-32603,"message":"Internal JSON-RPC error."},"id":1,"jsonrpc":"2.0"}"#.to_string() 
+            + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_polled_output() {
+        use output_agent::OutputAgentOptions;
+        
+        let (output_agent, mut driver) = 
+            OutputAgent::start_polled(WriteLineMessageWriter(vec![]), OutputAgentOptions::default());
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_request("sample_fn", Box::new(sample_fn)).unwrap();
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 1, "method": "sample_fn", "params": {"x": 1, "y": 2} }"#);
+        endpoint.send_notification("done", ()).unwrap();
+        endpoint.request_shutdown();
+        
+        (&mut driver).wait().unwrap();
+        assert_equal(String::from_utf8(driver.get_ref().0.clone()).unwrap(), 
+            r#"{"jsonrpc":"2.0","id":1,"result":"12"}"#.to_string() + "\n" + 
+            r#"{"jsonrpc":"2.0","method":"done"}"# + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_flush_failure() {
+        use output_agent::OutputAgentOptions;
+        use service_util::MessageWriter;
+        
+        struct UnflushableWriter;
+        
+        impl MessageWriter for UnflushableWriter {
+            fn write_message(&mut self, _msg: &str) -> Result<(), GError> {
+                Ok(())
+            }
+            
+            fn flush(&mut self) -> Result<(), GError> {
+                Err("Broken pipe".into())
+            }
+        }
+        
+        let options = OutputAgentOptions { flush_when_idle : true, .. OutputAgentOptions::default() };
+        
+        // The request is written, but the flush fails, which fails the request
+        let output_agent = OutputAgent::start_with_provider_and_options(|| UnflushableWriter, options);
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let future = endpoint.send_request::<_, (), ()>("first", ()).unwrap();
+        assert_equal(future.wait().unwrap(), RequestResult::RequestError(error_JSON_RPC_OutputFailed("Broken pipe")));
+        assert!(endpoint.is_shutdown());
+        endpoint.shutdown_and_join();
+        
+        let (output_agent, mut driver) = OutputAgent::start_polled(UnflushableWriter, options);
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let future = endpoint.send_request::<_, (), ()>("first", ()).unwrap();
+        endpoint.request_shutdown();
+        (&mut driver).wait().unwrap();
+        assert_equal(future.wait().unwrap(), RequestResult::RequestError(error_JSON_RPC_OutputFailed("Broken pipe")));
+        assert!(endpoint.inner.pending_requests.lock().unwrap().is_empty());
+    }
+    
+    #[test]
+    fn test_EndpointHandler_message_stream() {
+        use output_agent::OutputAgentOptions;
+        use service_util::SinkMessageWriter;
+        use futures::Sink;
+        use futures::Stream;
+        use futures::sync::mpsc;
+        
+        // The peer's input and output are channels
+        let (input_tx, input_rx) = mpsc::unbounded::<String>();
+        let (output_tx, output_rx) = mpsc::unbounded::<String>();
+        
+        let options = OutputAgentOptions { flush_when_idle : true, .. OutputAgentOptions::default() };
+        let (output_agent, mut driver) = OutputAgent::start_polled(SinkMessageWriter::new(output_tx), options);
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_request("sample_fn", Box::new(sample_fn)).unwrap();
+        let eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+        
+        // The peer sends a request, and ends the input once it has received the response. 
+        // It is polled on the same thread as the read loop and the driver, so each of them 
+        // must return NotReady rather than block, for the others to make progress.
+        let request = r#"{ "jsonrpc": "2.0", "id": 1, "method": "sample_fn", "params": {"x": 1, "y": 2} }"#;
+        let peer = Sink::send(input_tx, request.to_string())
+            .map_err(|_| -> GError { "Input closed.".into() })
+            .and_then(|input_tx| {
+                output_rx.into_future()
+                    .map(move |(response, _)| { drop(input_tx); response })
+                    .map_err(|_| -> GError { "Output closed.".into() })
+            });
+        let input = input_rx.map_err(|()| -> GError { "Input failed.".into() });
+        
+        // Input ended, so shutdown was requested, and the driver completes too
+        let driver_future = (&mut driver).map_err(|()| -> GError { "Output driver failed.".into() });
+        let (_, _, response) = eh.read_message_stream(input).join3(driver_future, peer).wait().unwrap();
+        assert!(endpoint.is_shutdown());
+        assert_equal(response, Some(r#"{"jsonrpc":"2.0","id":1,"result":"12"}"#.to_string()));
+        
+        // Stream error
+        let (output_agent, output) = new_capturing_output_agent();
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        let input = futures::stream::iter_result(vec![Err("Connection reset.".into())] as Vec<Result<String, GError>>);
+        check_err_contains(eh.read_message_stream(input).wait().unwrap_err(), "Connection reset.");
+        assert!(endpoint.is_shutdown());
+        assert_equal(captured_output(&endpoint, output), "".to_string());
+    }
+    
+    #[test]
+    fn test_Endpoint_bounded_queue() {
+        use output_agent::*;
+        use output_agent::test_util::start_paused_agent;
+        use std::sync::mpsc;
+        
+        let bound = TaskQueueBound::Bounded { capacity : 1, when_full : QueueFullPolicy::Fail };
+        let (output_agent, resume_tx, output) = start_paused_agent(bound);
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        
+        endpoint.send_notification("first", ()).unwrap();
+        check_err_contains(endpoint.send_notification("second", ()).unwrap_err(), "Output agent task queue is full.");
+        
+        let result = endpoint.send_request::<_, (), ()>("third", ());
+        check_err_contains(result.err().unwrap(), "Output agent task queue is full.");
+        // The failed request is no longer pending
+        assert!(endpoint.inner.pending_requests.lock().unwrap().is_empty());
+        
+        resume_tx.send(()).unwrap();
+        assert_equal(captured_output(&endpoint, output), 
+            r#"{"jsonrpc":"2.0","method":"first"}"#.to_string() + "\n");
+        
+        // A sender blocked on a full queue doesn't block other users of the Endpoint
+        let bound = TaskQueueBound::Bounded { capacity : 1, when_full : QueueFullPolicy::Block };
+        let (output_agent, resume_tx, output) = start_paused_agent(bound);
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        
+        endpoint.send_notification("first", ()).unwrap();
+        let blocked_endpoint = endpoint.clone();
+        let (started_tx, started_rx) = mpsc::channel::<()>();
+        let sender_thread = thread::spawn(move || {
+            started_tx.send(()).unwrap();
+            blocked_endpoint.send_notification("second", ()).unwrap();
+        });
+        started_rx.recv().unwrap();
+        assert!(!endpoint.is_shutdown());
+        assert!(endpoint.output_failure().is_none());
+        
+        resume_tx.send(()).unwrap();
+        sender_thread.join().unwrap();
+        assert_equal(captured_output(&endpoint, output), 
+            r#"{"jsonrpc":"2.0","method":"first"}"#.to_string() + "\n" + 
+            r#"{"jsonrpc":"2.0","method":"second"}"# + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_priority() {
+        use output_agent::*;
+        use output_agent::test_util::start_paused_agent;
+        
+        let (output_agent, resume_tx, output) = start_paused_agent(TaskQueueBound::Unbounded);
+        let options = EndpointOptions { control_methods : vec!["$/cancelRequest".into()], .. test_options() };
+        let endpoint = Endpoint::start_with_options(output_agent, options);
+        
+        endpoint.send_notification("progress", [1]).unwrap();
+        endpoint.write_request(Some(Id::Number(1)), "request", ()).unwrap();
+        endpoint.send_notification_with_priority("progress", [2], TaskPriority::Low).unwrap();
+        endpoint.submit_message(Response::new_result(Id::Number(7), Value::Null).into()).unwrap();
+        endpoint.send_notification("$/cancelRequest", ()).unwrap();
+        endpoint.send_notification("other", ()).unwrap();
+        
+        resume_tx.send(()).unwrap();
+        let output = captured_output(&endpoint, output);
+        let lines : Vec<&str> = output.lines().collect();
+        // Only responses and control messages jump the queue, other messages keep their order
+        assert_equal(lines, vec![
+            r#"{"jsonrpc":"2.0","id":7,"result":null}"#,
+            r#"{"jsonrpc":"2.0","method":"$/cancelRequest"}"#,
+            r#"{"jsonrpc":"2.0","method":"progress","params":[1]}"#,
+            r#"{"jsonrpc":"2.0","id":1,"method":"request"}"#,
+            r#"{"jsonrpc":"2.0","method":"other"}"#,
+            r#"{"jsonrpc":"2.0","method":"progress","params":[2]}"#,
+        ]);
+    }
+    
+    #[test]
+    fn test_Endpoint_invalid_messages() {
+        let (output_agent, output) = new_capturing_output_agent();
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "method": "#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": "abc", "method": 1 }"#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 7 }"#);
+        eh.handle_incoming_message(r#"[1, 2]"#);
+        
+        let output = captured_output(&endpoint, output);
+        let lines : Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        check_err_contains(lines[0], r#""id":null,"error":{"code":-32700,"#);
+        check_err_contains(lines[1], r#""id":"abc","error":{"code":-32600,"#);
+        // Neither a request nor a response, so the id can't be trusted
+        check_err_contains(lines[2], r#""id":null,"error":{"code":-32600,"#);
+        check_err_contains(lines[3], r#""id":null,"error":{"code":-32600,"#);
+    }
+    
+    #[test]
+    fn test_Endpoint_invalid_response() {
+        let (output_agent, output) = new_capturing_output_agent();
+        let mut endpoint = Endpoint::start_with_options(output_agent, EndpointOptions::default());
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        
+        let future : RequestFuture<String, ()> = endpoint.send_request("foo", ()).unwrap();
+        
+        // Nothing is replied to an invalid response, the pending request fails instead
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 1, "error": { "code": "x" } }"#);
+        assert_eq!(future.wait().unwrap(), RequestResult::RequestError(error_JSON_RPC_InvalidResponse(
+            r#"Value `"x"` of property `error.code` is not an Integer."#)));
+        assert!(endpoint.inner.pending_requests.lock().unwrap().is_empty());
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 7, "result": 1, "error": 2 }"#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": "abc", "method": 1 }"#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 8 }"#);
+        
+        let output = captured_output(&endpoint, output);
+        let lines : Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        // Error replies are written with high priority, ahead of the request
+        let abc_reply = r#"{"jsonrpc":"2.0","id":"abc","error":{"code":-32600,"#.to_string() + 
+            r#""message":"The JSON sent is not a valid Request object: "# + 
+            r#"Value `1` of property `method` is not a String."}}"#;
+        assert_equal(lines[0].to_string(), abc_reply);
+        check_err_contains(lines[1], r#""id":null,"error":{"code":-32600,"#);
+        assert_equal(lines[2].to_string(), r#"{"jsonrpc":"2.0","id":1,"method":"foo","params":null}"#.to_string());
+    }
+    
+    #[test]
+    fn test_MapRequestHandler_discover() {
+        use map_request_handler::MethodDescription;
+        use json_util::test_util::from_json;
+        
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.title = "Sample".into();
+        request_handler.version = "1.0".into();
+        request_handler.add_request("no_schema", Box::new(sample_fn)).unwrap();
+        request_handler.add_request_with_schema("sample_fn", Box::new(sample_fn)).unwrap();
+        request_handler.add_request_with_schema("error_data", 
+            Box::new(|_params: ()| -> MethodResult<bool, Vec<String>> { Ok(true) })).unwrap();
+        request_handler.add_notification("notify", Box::new(|_params: ()| {})).unwrap();
+        request_handler.add_notification_with_schema("notify_point", Box::new(|_params: Point| {})).unwrap();
+        request_handler.add_described_rpc_handler("described", new(async_method), MethodDescription {
+            params_schema : Some(from_json(r#"{"type":"array","items":{"type":"integer"}}"#)),
+            .. MethodDescription::default()
+        }).unwrap();
+        
+        let expected : Value = from_json(r#"{"openrpc":"1.2.6","info":{"title":"Sample","version":"1.0"},"methods":[
+            {"name":"described","x-notification":false,"params":[
+                {"name":"params","required":true,"schema":{"type":"array","items":{"type":"integer"}}}
+            ],"result":{"name":"result","schema":{}}},
+            {"name":"error_data","x-notification":false,"params":[],
+                "result":{"name":"result","schema":{"type":"boolean"}},
+                "x-error-data":{"type":"array","items":{"type":"string"}}},
+            {"name":"no_schema","x-notification":false,"params":[],"result":{"name":"result","schema":{}}},
+            {"name":"notify","x-notification":true,"params":[]},
+            {"name":"notify_point","x-notification":true,"params":[
+                {"name":"x","required":true,"schema":{"type":"integer"}},
+                {"name":"y","required":true,"schema":{"type":"integer"}}
+            ]},
+            {"name":"sample_fn","x-notification":false,"params":[
+                {"name":"x","required":true,"schema":{"type":"integer"}},
+                {"name":"y","required":true,"schema":{"type":"integer"}}
+            ],"result":{"name":"result","schema":{"type":"string"}}}
+        ]}"#);
+        assert_equal(request_handler.describe(), expected.clone());
+        assert_equal(from_json::<Value>(&request_handler.to_openrpc_json()), expected.clone());
+        
+        invoke_method(&mut request_handler, "rpc.discover", RequestParams::None, 
+            move |result| assert_equal(result.unwrap(), ResponseResult::Result(expected.clone())));
+    }
+    
+    #[test]
+    fn test_MapRequestHandler_validate_params() {
+        use json_util::test_util::from_json;
+        use json_util::unwrap_object;
+        
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_validated_request("sample_fn", Box::new(sample_fn)).unwrap();
+        
+        let params = RequestParams::Object(unwrap_object(from_json(r#"{"x": 1, "y": 2}"#)));
+        invoke_method(&mut request_handler, "sample_fn", params, |result| 
+            assert_equal(result.unwrap(), ResponseResult::Result(Value::String("12".into())))
+        );
+        
+        let params = RequestParams::Object(unwrap_object(from_json(r#"{"x": "1"}"#)));
+        invoke_method(&mut request_handler, "sample_fn", params, |result| 
+            assert_equal(result.unwrap(), ResponseResult::Error(RequestError { 
+                code : -32602,
+                message : "Invalid method parameter(s): `params`: Required property `y` is missing. ".to_string() + 
+                    "`params.x`: Value is not of type `integer`.",
+                data : Some(from_json(r#"[
+                    {"path":"params","message":"Required property `y` is missing."},
+                    {"path":"params.x","message":"Value is not of type `integer`."}
+                ]"#)),
+            }))
+        );
+        
+        invoke_method(&mut request_handler, "sample_fn", RequestParams::None, |result| 
+            check_request(result.unwrap(), ResponseResult::Error(RequestError {
+                data : Some(from_json(r#"[{"path":"params","message":"Value is not of type `object`."}]"#)),
+                .. error_JSON_RPC_InvalidParams("`params`: Value is not of type `object`.")
+            })));
+    }
+    
+    #[test]
+    fn test_MapRequestHandler_param_names() {
+        use json_util::test_util::from_json;
+        
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_request_with_schema("sample_fn", Box::new(sample_fn)).unwrap();
+        request_handler.set_param_names("sample_fn", &["y", "x"]);
+        
+        let params = RequestParams::Array(from_json("[1, 2]"));
+        invoke_method(&mut request_handler, "sample_fn", params, |result| 
+            assert_equal(result.unwrap(), ResponseResult::Result(Value::String("21".into())))
+        );
+        let params = RequestParams::Object(json_util::unwrap_object(from_json(r#"{"x": 1, "y": 2}"#)));
+        invoke_method(&mut request_handler, "sample_fn", params, |result| 
+            assert_equal(result.unwrap(), ResponseResult::Result(Value::String("12".into())))
+        );
+        
+        let params = RequestParams::Array(from_json("[1]"));
+        invoke_method(&mut request_handler, "sample_fn", params, |result| 
+            check_request(result.unwrap(), 
+                ResponseResult::Error(error_JSON_RPC_InvalidParams_at("params.x", "Property is missing.")))
+        );
+        let params = RequestParams::Array(from_json("[1, 2, 3]"));
+        invoke_method(&mut request_handler, "sample_fn", params, |result| 
+            check_request(result.unwrap(), ResponseResult::Error(
+                error_JSON_RPC_InvalidParams("Expected at most 2 positional params, got 3.")))
+        );
+        
+        let method = request_handler.describe().find("methods").unwrap().as_array().unwrap()[0].clone();
+        assert_eq!(method.find("paramStructure"), Some(&Value::String("either".into())));
+        let param_names : Vec<&str> = method.find("params").unwrap().as_array().unwrap().iter()
+            .map(|param| param.find("name").unwrap().as_str().unwrap()).collect();
+        assert_eq!(param_names, vec!["y", "x"]);
+    }
+    
+    #[test]
+    fn test_MapRequestHandler_reserved_name() {
+        use map_request_handler::AddMethodError;
+        
+        let mut request_handler = MapRequestHandler::new();
+        let result = request_handler.add_request("rpc.foo", Box::new(no_params_method));
+        assert_eq!(result, Err(AddMethodError::ReservedName("rpc.foo".into())));
+        check_err_contains(result.unwrap_err(), "Method name `rpc.foo` is reserved for rpc-internal methods.");
+        let result = request_handler.add_rpc_handler("rpc.bar", new(async_method));
+        assert_eq!(result, Err(AddMethodError::ReservedName("rpc.bar".into())));
+        assert!(request_handler.method_handlers.is_empty());
+    }
+    
+    #[test]
+    fn test_Endpoint_strict_validation() {
+        let run = |options: EndpointOptions, message: &str| {
+            let (output_agent, output) = new_capturing_output_agent();
+            let endpoint = Endpoint::start_with_options(output_agent, options);
+            let mut eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+            eh.handle_incoming_message(message);
+            captured_output(&endpoint, output)
+        };
+        
+        let message = r#"{ "jsonrpc": "2.0", "id": 3, "method": "rpc.foo", "params": null }"#;
+        check_err_contains(run(EndpointOptions::default(), message), 
+            r#"{"jsonrpc":"2.0","id":3,"error":{"code":-32601,"#);
+        assert_equal(run(test_options(), message), 
+            r#"{"jsonrpc":"2.0","id":3,"error":{"code":-32600,"message":"The JSON sent is not a valid "#.to_string() + 
+            r#"Request object: Method name `rpc.foo` is reserved for rpc-internal methods. "# + 
+            r#"Member `params` is not an Object or Array.","data":["#  + 
+            r#""Method name `rpc.foo` is reserved for rpc-internal methods.","# + 
+            r#""Member `params` is not an Object or Array."]}}"# + "\n");
+        
+        // Nothing is replied to a response, the pending request fails instead
+        let (output_agent, output) = new_capturing_output_agent();
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        let future : RequestFuture<String, ()> = endpoint.send_request("foo", ()).unwrap();
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 1, "result": 1, "error": { "code": 1, "message": "x" } }"#);
+        let mut expected_error = error_JSON_RPC_InvalidResponse("Response has both `result` and `error`.");
+        expected_error.data = Some(Value::Array(vec![Value::String("Response has both `result` and `error`.".into())]));
+        assert_eq!(future.wait().unwrap(), RequestResult::RequestError(expected_error));
+        assert_equal(captured_output(&endpoint, output), r#"{"jsonrpc":"2.0","id":1,"method":"foo"}"#.to_string() + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_echo_id() {
+        let (output_agent, output) = new_capturing_output_agent();
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_request("no_params_method", Box::new(no_params_method)).unwrap();
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": -3, "method": "no_params_method" }"#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "id": 2.5, "method": "no_params_method" }"#);
+        
+        let output = captured_output(&endpoint, output);
+        assert_equal(output, 
+            r#"{"jsonrpc":"2.0","id":-3,"result":"okay"}"#.to_string() + "\n" + 
+            r#"{"jsonrpc":"2.0","id":2.5,"result":"okay"}"# + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_id_generator() {
+        use id_generator::PrefixIdGenerator;
+        
+        let (output_agent, output) = new_capturing_output_agent();
+        let options = EndpointOptions { 
+            id_generator : Arc::new(PrefixIdGenerator::new("c1-".into())), .. test_options() 
+        };
+        let mut endpoint = Endpoint::start_with_options(output_agent, options);
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        
+        let future : RequestFuture<String, ()> = endpoint.send_request("foo", ()).unwrap();
+        
+        let response = Response::new_result(Id::String("c1-1".into()), Value::String("result".into())); 
+        eh.handle_incoming_message(&to_json(&response));
+        assert_eq!(future.wait().unwrap(), RequestResult::MethodResult(Ok("result".to_string())));
+        
+        let output = captured_output(&endpoint, output);
+        assert_equal(output, r#"{"jsonrpc":"2.0","id":"c1-1","method":"foo"}"#.to_string() + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_unknown_response() {
+        let response = Response::new_result(Id::Number(42), Value::Null);
+        
+        // Test LogAndDrop
+        let (output_agent, output) = new_capturing_output_agent();
+        let mut endpoint = Endpoint::start_with_options(output_agent, test_options());
+        endpoint.handle_incoming_response(response.clone());
+        assert_equal(captured_output(&endpoint, output), "".to_string());
+        
+        // Test Callback
+        let received = newArcMutex(vec![]);
+        let received2 = received.clone();
+        let (output_agent, output) = new_capturing_output_agent();
+        let options = EndpointOptions { 
+            unknown_response_policy : UnknownResponsePolicy::Callback(Arc::new(move |response: &Response| {
+                received2.lock().unwrap().push(response.id.clone());
+            })), 
+            .. test_options() 
+        };
+        let mut endpoint = Endpoint::start_with_options(output_agent, options);
+        endpoint.handle_incoming_response(response.clone());
+        assert_equal(captured_output(&endpoint, output), "".to_string());
+        assert_equal(received.lock().unwrap().clone(), vec![Id::Number(42)]);
+        
+        // Test NotifyPeer
+        let (output_agent, output) = new_capturing_output_agent();
+        let options = EndpointOptions { 
+            unknown_response_policy : UnknownResponsePolicy::NotifyPeer("$/unknownResponse".into()), 
+            .. test_options() 
+        };
+        let mut endpoint = Endpoint::start_with_options(output_agent, options);
+        endpoint.handle_incoming_response(response.clone());
+        assert_equal(captured_output(&endpoint, output), 
+            r#"{"jsonrpc":"2.0","method":"$/unknownResponse","params":{"error":{"code":-32000,"#.to_string() + 
+            r#""message":"Invalid method response: id `42` not found"},"id":42}}"# + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_notification_errors() {
+        let errors = newArcMutex(vec![]);
+        let errors2 = errors.clone();
+        
+        let (output_agent, output) = new_capturing_output_agent();
+        let options = EndpointOptions { 
+            notification_error_hook : Arc::new(move |method_name: &str, error: &RequestError| {
+                errors2.lock().unwrap().push((method_name.to_string(), error.code));
+            }), 
+            .. test_options() 
+        };
+        let endpoint = Endpoint::start_with_options(output_agent, options);
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_request("sample_fn", Box::new(sample_fn)).unwrap();
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+        
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "method": "unknown_method" }"#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "method": "sample_fn", "params": {} }"#);
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "method": "sample_fn", "params": {"x": 1, "y": 2} }"#);
+        
+        assert_equal(captured_output(&endpoint, output), "".to_string());
+        assert_equal(errors.lock().unwrap().clone(), vec![
+            ("unknown_method".to_string(), -32601), 
+            ("sample_fn".to_string(), -32602),
+        ]);
+    }
+    
+    #[test]
+    fn test_Endpoint_protocol_version() {
+        let v1_request = r#"{ "id": 1, "method": "no_params_method", "params": [] }"#;
+        let v2_request = r#"{ "jsonrpc": "2.0", "id": 2, "method": "no_params_method" }"#;
+        
+        let run = |options: EndpointOptions, messages: &[&str]| {
+            let (output_agent, output) = new_capturing_output_agent();
+            let endpoint = Endpoint::start_with_options(output_agent, options);
+            let mut request_handler = MapRequestHandler::new();
+            request_handler.add_request("no_params_method", Box::new(no_params_method)).unwrap();
+            let mut eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+            for message in messages {
+                eh.handle_incoming_message(message);
+            }
+            captured_output(&endpoint, output)
+        };
+        
+        let v1_options = || EndpointOptions { 
+            format : MessageFormat { version : ProtocolVersion::V1, .. MessageFormat::default() },
+            .. test_options() 
+        };
+        assert_equal(run(v1_options(), &[v1_request]), 
+            r#"{"id":1,"result":"okay","error":null}"#.to_string() + "\n");
+        
+        // 2.0 mode rejects 1.0 messages
+        check_err_contains(run(EndpointOptions::default(), &[v1_request]), "Property `jsonrpc` is missing.");
+        check_err_contains(run(test_options(), &[v1_request]), "Member `jsonrpc` is missing.");
+        
+        // Auto-detect
+        let auto_options = || EndpointOptions { auto_detect_version : true, .. test_options() };
+        assert_equal(run(auto_options(), &[v1_request, v2_request]), 
+            r#"{"id":1,"result":"okay","error":null}"#.to_string() + "\n" + 
+            r#"{"id":2,"result":"okay","error":null}"# + "\n");
+        assert_equal(run(auto_options(), &[v2_request]), 
+            r#"{"jsonrpc":"2.0","id":2,"result":"okay"}"#.to_string() + "\n");
+        check_err_contains(run(auto_options(), &[v2_request, v1_request]), "Member `jsonrpc` is missing.");
+        // Messages that are not Objects don't lock the version
+        let output = run(auto_options(), &["[1, 2]", "garbage", v2_request]);
+        assert!(output.ends_with(&(r#"{"jsonrpc":"2.0","id":2,"result":"okay"}"#.to_string() + "\n")), output);
+    }
+    
+    #[test]
+    fn test_Endpoint_extensions() {
+        let (output_agent, output) = new_capturing_output_agent();
+        let endpoint = Endpoint::start_with_options(output_agent, test_options());
+        
+        let mut request_handler = MapRequestHandler::new();
+        request_handler.add_rpc_handler("echo_meta", new(|_params, mut completable: ResponseCompletable| {
+            let meta = completable.request_extensions().get("meta").cloned().unwrap_or(Value::Null);
+            let mut extensions = new_object();
+            extensions.insert("meta".into(), meta);
+            completable.set_response_extensions(extensions);
+            completable.complete(Some(ResponseResult::Result(Value::Null)));
+        })).unwrap();
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(request_handler));
+        eh.handle_incoming_message(r#"{"jsonrpc":"2.0","id":1,"method":"echo_meta","meta":{"trace":"t1"}}"#);
+        
+        let mut extensions = new_object();
+        extensions.insert("meta".into(), Value::String("t2".into()));
+        endpoint.write_request_with_extensions(None, "foo", Value::Null, extensions).unwrap();
+        
+        assert_equal(captured_output(&endpoint, output), 
+            r#"{"jsonrpc":"2.0","id":1,"result":null,"meta":{"trace":"t1"}}"#.to_string() + "\n" +
+            r#"{"jsonrpc":"2.0","method":"foo","meta":"t2"}"# + "\n");
+    }
+    
+    #[test]
+    fn test_Endpoint_limits() {
+        use message_codec::MessageLimits;
+        
+        let (output_agent, output) = new_capturing_output_agent();
+        let limits = MessageLimits { max_message_size : 100, max_array_len : 1, .. MessageLimits::default() };
+        let endpoint = Endpoint::start_with_options(output_agent, 
+            EndpointOptions { limits : limits, .. test_options() });
+        let mut eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        
+        eh.handle_incoming_message(&format!(r#"{{ "jsonrpc": "2.0", "method": "{}" }}"#, "x".repeat(100)));
+        eh.handle_incoming_message(r#"{ "jsonrpc": "2.0", "method": "foo", "params": [1, 2] }"#);
+        
+        let output = captured_output(&endpoint, output);
+        let lines : Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        check_err_contains(lines[0], "Message size 134 exceeds limit of 100 bytes.");
+        check_err_contains(lines[0], r#""code":-32600"#);
+        check_err_contains(lines[1], "Array length 2 exceeds limit of 1.");
+        
+        // The read loop applies the Endpoint's limits to the reader: an oversized message is not buffered
+        let (output_agent, output) = new_capturing_output_agent();
+        let limits = MessageLimits { max_message_size : 100, .. MessageLimits::default() };
+        let endpoint = Endpoint::start_with_options(output_agent, 
+            EndpointOptions { limits : limits, .. test_options() });
+        let eh = EndpointHandler::create(endpoint.clone(), new(MapRequestHandler::new()));
+        let input = [0, 0, 0, 134, b'{'];
+        let mut reader = LengthPrefixedMessageReader::new(&input[..]);
+        check_err_contains(eh.run_message_read_loop(&mut reader).unwrap_err(), 
+            "Message length 134 exceeds maximum size of 100 bytes.");
+        assert_equal(captured_output(&endpoint, output), "".to_string());
+    }
+    
     pub fn noop_unpark() -> Arc<Unpark> {
         struct Foo;
         
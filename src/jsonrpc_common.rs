@@ -6,6 +6,8 @@
 // except according to those terms.
 
 use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
 
 use serde;
 use serde::de::Visitor;
@@ -14,6 +16,7 @@ use serde::Error;
 use serde_json::Value;
 use serde_json;
 use json_util::*;
+use json_path::PathError;
 
 
 
@@ -27,10 +30,59 @@ pub fn parse_jsonrpc_id(id: Value) -> JsonRpcParseResult<Option<Id>> {
 
 /* ----------------- Id ----------------- */
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone)]
 /// A JSON RPC Id
-/// Note: only supports u64 numbers
-pub enum Id { Number(u64), String(String), Null, }
+/// 
+/// Numbers keep the form they were received in, so that they can be echoed back exactly:
+/// non-negative integers are `Number`, negative integers are `Integer`, and fractional numbers are `Float`.
+/// 
+/// For equality and hashing, an `Integer` is the same as the equivalent `Number`,
+/// and a `Float` is compared by its bit pattern.
+pub enum Id { Number(u64), Integer(i64), Float(f64), String(String), Null, }
+
+impl Id {
+    /// The non-negative integer value of this id, if any.
+    fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Id::Number(number) => Some(number),
+            Id::Integer(number) if number >= 0 => Some(number as u64),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for Id {
+    fn eq(&self, other: &Id) -> bool {
+        if let (Some(left), Some(right)) = (self.as_u64(), other.as_u64()) {
+            return left == right;
+        }
+        match (self, other) {
+            (&Id::Integer(left), &Id::Integer(right)) => left == right,
+            (&Id::Float(left), &Id::Float(right)) => left.to_bits() == right.to_bits(),
+            (&Id::String(ref left), &Id::String(ref right)) => left == right,
+            (&Id::Null, &Id::Null) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Id {}
+
+impl Hash for Id {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if let Some(number) = self.as_u64() {
+            0u8.hash(state);
+            number.hash(state);
+            return;
+        }
+        match *self {
+            Id::Integer(number) => { 1u8.hash(state); number.hash(state); }
+            Id::Float(number) => { 2u8.hash(state); number.to_bits().hash(state); }
+            Id::String(ref string) => { 3u8.hash(state); string.hash(state); }
+            Id::Null | Id::Number(_) => { 4u8.hash(state); }
+        }
+    }
+}
 
 impl serde::Serialize for Id {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
@@ -39,6 +91,8 @@ impl serde::Serialize for Id {
         match *self {
             Id::Null => serializer.serialize_none(),
             Id::Number(number) => serializer.serialize_u64(number), 
+            Id::Integer(number) => serializer.serialize_i64(number), 
+            Id::Float(number) => serializer.serialize_f64(number), 
             Id::String(ref string) => serializer.serialize_str(string),
         }
     }
@@ -67,6 +121,20 @@ impl Visitor for IdDeserializeVisitor {
         Ok(Id::Number(value))
     }
     
+    fn visit_i64<E>(&mut self, value: i64) -> Result<Self::Value, E> where E: Error,
+    {
+        if value >= 0 {
+            Ok(Id::Number(value as u64))
+        } else {
+            Ok(Id::Integer(value))
+        }
+    }
+    
+    fn visit_f64<E>(&mut self, value: f64) -> Result<Self::Value, E> where E: Error,
+    {
+        Ok(Id::Float(value))
+    }
+    
     fn visit_str<E>(&mut self, value: &str) -> Result<Self::Value, E> where E: Error,
     {
         Ok(Id::String(value.to_string()))
@@ -88,9 +156,52 @@ fn test_Id() {
     test_serde(&Id::String("123".into()));
     test_serde(&Id::String("".into()));
     test_serde(&Id::String("foo".into()));
+    test_serde(&Id::Number(u64::max_value()));
+    test_serde(&Id::Integer(-123));
+    test_serde(&Id::Integer(i64::min_value()));
+    test_serde(&Id::Float(1.5));
+    test_serde(&Id::Float(-0.25));
+    
+    assert_eq!(from_json::<Id>("-123"), Id::Integer(-123)); 
+    assert_eq!(from_json::<Id>("12.5"), Id::Float(12.5)); 
+    assert_eq!(to_json(&Id::Integer(-123)), "-123"); 
+    assert_eq!(to_json(&Id::Float(12.5)), "12.5"); 
+    
+    // Test Eq and Hash
+    use std::collections::HashSet;
     
-    // FIXME better handling of non-u64 numbers?
-//    assert_eq!(from_json::<Id>("-123"), Id::Number(123)); 
+    assert_eq!(Id::Integer(5), Id::Number(5));
+    assert!(Id::Integer(-5) != Id::Number(5));
+    assert!(Id::Float(5.0) != Id::Number(5));
+    assert!(Id::String("5".into()) != Id::Number(5));
+    
+    let mut ids = HashSet::new();
+    ids.insert(Id::Number(5));
+    ids.insert(Id::Float(0.5));
+    ids.insert(Id::Integer(-1));
+    assert!(ids.contains(&Id::Integer(5)));
+    assert!(ids.contains(&Id::Float(0.5)));
+    assert!(ids.contains(&Id::Integer(-1)));
+    assert!(!ids.contains(&Id::Float(-1.0)));
+    assert!(!ids.contains(&Id::Null));
+}
+
+
+/* -----------------  Method names  ----------------- */
+
+/// From the spec: method names that begin with `rpc.` are reserved for rpc-internal methods and extensions.
+pub const RESERVED_METHOD_PREFIX : &'static str = "rpc.";
+
+/// The rpc-internal method that returns an OpenRPC description of the service.
+pub const RPC_DISCOVER_METHOD : &'static str = "rpc.discover";
+
+pub fn is_reserved_method_name(method_name: &str) -> bool {
+    method_name.starts_with(RESERVED_METHOD_PREFIX)
+}
+
+/// Whether given name is an rpc-internal method provided by this library.
+pub fn is_known_rpc_internal_method(method_name: &str) -> bool {
+    method_name == RPC_DISCOVER_METHOD
 }
 
 
@@ -107,6 +218,21 @@ impl RequestError {
     pub fn new(code: i64, message: String) -> RequestError {
         RequestError { code : code, message : message, data : None }
     }
+    
+    /// Deserialize a RequestError from given value, with errors located by `helper`.
+    pub fn from_value_with<ERR, HELPER>(helper: &mut HELPER, value: Value) -> Result<RequestError, ERR>
+    where 
+        HELPER: JsonDeserializerHelper<ERR>, 
+    {
+        let mut json_obj = try!(helper.as_Object(value));
+        
+        let code = try!(helper.obtain_i64(&mut json_obj, "code"));
+        let message = try!(helper.obtain_String(&mut json_obj, "message"));
+        
+        let data = json_obj.remove("data"); 
+        
+        Ok(RequestError{ code : code, message : message, data : data }) 
+    }
 }
 
 pub fn error_JSON_RPC_ParseError<T: fmt::Display>(error: T) -> RequestError { 
@@ -121,6 +247,18 @@ pub fn error_JSON_RPC_MethodNotFound() -> RequestError {
 pub fn error_JSON_RPC_InvalidParams<T: fmt::Display>(error: T) -> RequestError { 
     RequestError::new(-32602, format!("Invalid method parameter(s): {}", error).to_string())
 }
+/// An Invalid Params error for given located errors, with each error's `path` and `message` 
+/// in the `data` array.
+pub fn error_JSON_RPC_InvalidParams_for(errors: &[PathError]) -> RequestError { 
+    let messages : Vec<String> = errors.iter().map(|error| format!("{}", error)).collect();
+    let mut error = error_JSON_RPC_InvalidParams(messages.join(" "));
+    error.data = Some(Value::Array(errors.iter().map(PathError::to_value).collect()));
+    error
+}
+/// An Invalid Params error for the parameter value at `path`.
+pub fn error_JSON_RPC_InvalidParams_at(path: &str, message: &str) -> RequestError { 
+    error_JSON_RPC_InvalidParams_for(&[PathError::new(path.to_string(), message.to_string())])
+}
 pub fn error_JSON_RPC_InternalError() -> RequestError { 
     RequestError::new(-32603, "Internal JSON-RPC error.".to_string())
 }
@@ -128,6 +266,10 @@ pub fn error_JSON_RPC_InternalError() -> RequestError {
 pub fn error_JSON_RPC_InvalidResponse<T: fmt::Display>(error: T) -> RequestError { 
     RequestError::new(-32000, format!("Invalid method response: {}", error).to_string())
 }
+/// The error of a request that can't get a response, because the endpoint's output failed.
+pub fn error_JSON_RPC_OutputFailed<T: fmt::Display>(error: T) -> RequestError { 
+    RequestError::new(-32001, format!("Endpoint output failed: {}", error).to_string())
+}
 
 impl serde::Serialize for RequestError {
     fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
@@ -152,14 +294,7 @@ impl serde::Deserialize for RequestError {
     {
         let mut helper = SerdeJsonDeserializerHelper(deserializer);
         let value : Value = try!(Value::deserialize(helper.0));
-        let mut json_obj = try!(helper.as_Object(value));
-        
-        let code = try!(helper.obtain_i64(&mut json_obj, "code"));
-        let message = try!(helper.obtain_String(&mut json_obj, "message"));
-        
-        let data = json_obj.remove("data"); 
-        
-        Ok(RequestError{ code : code, message : message, data : data }) 
+        RequestError::from_value_with(&mut helper, value)
     }
 }
 
@@ -174,4 +309,9 @@ fn test_RequestError() {
     test_serde(&RequestError{ code : 1, message : "xxx".into(), data : Some(Value::String("asdf".into())) });
     
     test_error_de::<RequestError>("{}", "Property `code` is missing");
+    test_error_de::<RequestError>(r#"{"code":1, "message":2}"#, "Value `2` of property `message` is not a String.");
+    
+    let error = error_JSON_RPC_InvalidParams_at("params.range.start", "Property is missing.");
+    assert_eq!(error.message, "Invalid method parameter(s): `params.range.start`: Property is missing.");
+    assert_eq!(to_json(&error.data), r#"[{"message":"Property is missing.","path":"params.range.start"}]"#);
 }
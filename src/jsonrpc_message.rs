@@ -11,12 +11,69 @@ extern crate serde;
 
 use serde_json::Value;
 
+use jsonrpc_common::Id;
+use jsonrpc_common::is_reserved_method_name;
+use jsonrpc_common::is_known_rpc_internal_method;
 use jsonrpc_request::*;
 use jsonrpc_response::*;
 use json_util::*;
 
 /* -----------------  Message  ----------------- */
 
+/// The JSON-RPC protocol version of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    /// JSON-RPC 1.0: no `jsonrpc` property, `params` always an Array, notifications have a `null` id,
+    /// and responses have both `result` and `error`, one of them `null`.
+    V1,
+    /// JSON-RPC 2.0
+    V2,
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::V2
+    }
+}
+
+/// Detect the protocol version of given message: 2.0 if it has `"jsonrpc": "2.0"`, 1.0 otherwise.
+/// Returns None if the message is not an Object, since then it's not a message of either version.
+pub fn detect_protocol_version(value: &Value) -> Option<ProtocolVersion> {
+    let json_obj = match *value {
+        Value::Object(ref json_obj) => json_obj,
+        _ => return None,
+    };
+    if let Some(&Value::String(ref jsonrpc)) = json_obj.get("jsonrpc") {
+        if jsonrpc == "2.0" {
+            return Some(ProtocolVersion::V2);
+        }
+    }
+    Some(ProtocolVersion::V1)
+}
+
+/// Options controlling how messages are serialized.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MessageFormat {
+    pub version : ProtocolVersion,
+    /// If true, `RequestParams::None` is serialized by omitting the `params` property, 
+    /// instead of as `"params": null`. Some peers reject `null` params. Only applies to 2.0.
+    pub omit_none_params : bool,
+}
+
+/// A message, together with the format to serialize it with.
+pub struct FormattedMessage<'a>(pub &'a Message, pub &'a MessageFormat);
+
+impl<'a> serde::Serialize for FormattedMessage<'a> {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        match *self.0 {
+            Message::Request(ref request) => request.serialize_with_format(serializer, self.1),
+            Message::Response(ref response) => response.serialize_with_format(serializer, self.1),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Message {
     Request(Request),
@@ -46,20 +103,161 @@ impl serde::Serialize for Message {
     }
 }
 
-impl serde::Deserialize for Message {
-    fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+impl Message {
+    pub fn deserialize_with_version<DE>(deserializer: &mut DE, version: ProtocolVersion) -> Result<Self, DE::Error>
         where DE: serde::Deserializer 
     {
         let mut helper = SerdeJsonDeserializerHelper(deserializer);
-        let value = try!(Value::deserialize(helper.0));
+        let value = try!(<Value as serde::Deserialize>::deserialize(helper.0));
         let json_obj = try!(helper.as_Object(value));
         
         if json_obj.contains_key("method") {
-            let request = serde_json::from_value::<Request>(Value::Object(json_obj));
-            Ok(Message::Request(try!(request.map_err(to_de_error))))
+            let request = Request::from_value(Value::Object(json_obj), version);
+            Ok(Message::Request(try!(request.map_err(json_to_de_error))))
         } else {
-            let response = serde_json::from_value::<Response>(Value::Object(json_obj));
-            Ok(Message::Response(try!(response.map_err(to_de_error))))
+            let response = Response::from_value(Value::Object(json_obj), version);
+            Ok(Message::Response(try!(response.map_err(json_to_de_error))))
+        }
+    }
+    
+    pub fn from_value(value: Value, version: ProtocolVersion) -> Result<Self, serde_json::Error> {
+        Self::deserialize_with_version(&mut serde_json::value::Deserializer::new(value), version)
+    }
+}
+
+impl serde::Deserialize for Message {
+    fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+        where DE: serde::Deserializer 
+    {
+        Self::deserialize_with_version(deserializer, ProtocolVersion::V2)
+    }
+}
+
+/// Recover the id of a message that is well-formed JSON, but not a valid Message.
+/// Returns `Id::Null` if there is no valid id.
+pub fn recover_message_id(value: &Value) -> Id {
+    if let Value::Object(ref json_obj) = *value {
+        if let Some(id) = json_obj.get("id") {
+            if let Ok(id) = serde_json::from_value::<Id>(id.clone()) {
+                return id;
+            }
+        }
+    }
+    Id::Null
+}
+
+/// The kind of a message that is well-formed JSON, but possibly not a valid Message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    /// An Object with a `method` member.
+    Request,
+    /// An Object with a `result` or `error` member, but no `method`.
+    Response,
+    /// Neither of the above.
+    Unknown,
+}
+
+pub fn classify_message(value: &Value) -> MessageKind {
+    if let Value::Object(ref json_obj) = *value {
+        if json_obj.contains_key("method") {
+            return MessageKind::Request;
+        }
+        if json_obj.contains_key("result") || json_obj.contains_key("error") {
+            return MessageKind::Response;
+        }
+    }
+    MessageKind::Unknown
+}
+
+/* -----------------  Strict validation  ----------------- */
+
+/// Check given message against the MUST rules of the JSON-RPC 2.0 spec, 
+/// returning a description of each violation found (empty if the message is conformant).
+/// 
+/// This is stricter than parsing a `Message`, which accepts some non-conformant messages.
+/// Extension members are allowed.
+pub fn check_spec_violations(value: &Value) -> Vec<String> {
+    let mut violations = vec![];
+    
+    let json_obj = match *value {
+        Value::Object(ref json_obj) => json_obj,
+        _ => {
+            violations.push("Message is not an Object.".to_string());
+            return violations;
+        }
+    };
+    
+    match json_obj.get("jsonrpc") {
+        None => violations.push("Member `jsonrpc` is missing.".to_string()),
+        Some(&Value::String(ref jsonrpc)) if jsonrpc == "2.0" => {}
+        Some(_) => violations.push(r#"Member `jsonrpc` is not exactly "2.0"."#.to_string()),
+    }
+    
+    if let Some(id) = json_obj.get("id") {
+        match *id {
+            Value::String(_) | Value::I64(_) | Value::U64(_) | Value::F64(_) | Value::Null => {}
+            _ => violations.push("Member `id` is not a String, Number, or Null.".to_string()),
+        }
+    }
+    
+    let is_request = json_obj.contains_key("method");
+    let is_response = json_obj.contains_key("result") || json_obj.contains_key("error");
+    
+    if is_request && is_response {
+        violations.push("Message has both Request members (`method`) and Response members (`result`, `error`).".to_string());
+    } else if is_request {
+        check_request_violations(json_obj, &mut violations);
+    } else if is_response {
+        check_response_violations(json_obj, &mut violations);
+    } else if json_obj.contains_key("id") {
+        violations.push("Response has neither `result` nor `error`.".to_string());
+    } else {
+        violations.push("Message is not a Request (no `method`), and as a Response has neither `result` nor `error`.".to_string());
+    }
+    
+    violations
+}
+
+fn check_request_violations(json_obj: &JsonObject, violations: &mut Vec<String>) {
+    match json_obj.get("method") {
+        Some(&Value::String(ref method)) => {
+            if is_reserved_method_name(method) && !is_known_rpc_internal_method(method) {
+                violations.push(format!("Method name `{}` is reserved for rpc-internal methods.", method));
+            }
+        }
+        _ => violations.push("Member `method` is not a String.".to_string()),
+    }
+    
+    match json_obj.get("params") {
+        None | Some(&Value::Object(_)) | Some(&Value::Array(_)) => {}
+        Some(_) => violations.push("Member `params` is not an Object or Array.".to_string()),
+    }
+}
+
+fn check_response_violations(json_obj: &JsonObject, violations: &mut Vec<String>) {
+    if !json_obj.contains_key("id") {
+        violations.push("Member `id` is missing.".to_string());
+    }
+    
+    if json_obj.contains_key("result") && json_obj.contains_key("error") {
+        violations.push("Response has both `result` and `error`.".to_string());
+    }
+    
+    if let Some(error) = json_obj.get("error") {
+        let error_obj = match *error {
+            Value::Object(ref error_obj) => error_obj,
+            _ => {
+                violations.push("Member `error` is not an Object.".to_string());
+                return;
+            }
+        };
+        match error_obj.get("code") {
+            Some(&Value::I64(_)) | Some(&Value::U64(_)) => {}
+            _ => violations.push("Member `error.code` is not an Integer.".to_string()),
+        }
+        match error_obj.get("message") {
+            Some(&Value::String(_)) => {}
+            _ => violations.push("Member `error.message` is not a String.".to_string()),
         }
     }
 }
@@ -82,7 +280,7 @@ pub mod message_tests {
     fn test_Message() {
         
         // Attempt Method parse
-        test_error_de::<Message>(r#"{ "jsonrpc": "2.0", "method":"foo" }"#, "Property `params` is missing");
+        test_error_de::<Message>(r#"{ "jsonrpc": "2.0", "method":1 }"#, "Value `1` of property `method` is not a String.");
         
         // Attempt Response parse
         test_error_de::<Message>(r#"{ "jsonrpc": "2.0"}"#, "Property `id` is missing");
@@ -93,4 +291,120 @@ pub mod message_tests {
         test_serde::<Message>(&Request::new(1, "myMethod".to_string(), sample_params).into());
     }
     
+    #[test]
+    fn test_Message_V1() {
+        let v1 = MessageFormat { version : ProtocolVersion::V1, .. MessageFormat::default() };
+        let v1_message = |json: &str| Message::from_value(from_json(json), ProtocolVersion::V1).unwrap(); 
+        
+        let sample_params = unwrap_object(sample_json_obj(123));
+        let request = Request::new(1, "myMethod".to_string(), sample_params);
+        
+        // Request
+        let message = Message::Request(Request { params : RequestParams::Array(vec![Value::U64(1)]), .. request.clone() });
+        assert_eq!(to_json(&FormattedMessage(&message, &v1)), r#"{"id":1,"method":"myMethod","params":[1]}"#);
+        assert_eq!(v1_message(r#"{"id":1,"method":"myMethod","params":[1]}"#), message);
+        
+        let message = Message::Request(request.clone());
+        assert_eq!(to_json(&FormattedMessage(&message, &v1)), r#"{"id":1,"method":"myMethod","params":[{"foo":123}]}"#);
+        
+        // Notification
+        let message = Message::Request(Request { id : None, params : RequestParams::None, .. request.clone() });
+        assert_eq!(to_json(&FormattedMessage(&message, &v1)), r#"{"id":null,"method":"myMethod","params":[]}"#);
+        assert_eq!(v1_message(r#"{"id":null,"method":"myMethod"}"#), message);
+        
+        // Responses
+        let message = Message::Response(Response::new_result(Id::Number(1), Value::U64(2)));
+        assert_eq!(to_json(&FormattedMessage(&message, &v1)), r#"{"id":1,"result":2,"error":null}"#);
+        assert_eq!(v1_message(r#"{"id":1,"result":2,"error":null}"#), message);
+        
+        let message = Message::Response(Response::new_result(Id::Number(1), Value::Null));
+        assert_eq!(v1_message(r#"{"id":1,"result":null,"error":null}"#), message);
+        
+        let error = RequestError::new(1, "err".into());
+        let message = Message::Response(Response::new_error(Id::Number(1), error));
+        assert_eq!(to_json(&FormattedMessage(&message, &v1)), r#"{"id":1,"result":null,"error":{"code":1,"message":"err"}}"#);
+        assert_eq!(v1_message(r#"{"id":1,"result":null,"error":{"code":1,"message":"err"}}"#), message);
+        
+        // 2.0 messages are accepted too
+        let message = Message::Response(Response::new_result(Id::Number(1), Value::U64(2)));
+        assert_eq!(v1_message(r#"{"jsonrpc":"2.0","id":1,"result":2}"#), message);
+        
+        // but not the other way around
+        test_error_de::<Message>(r#"{"id":1,"result":2,"error":null}"#, "Property `jsonrpc` is missing");
+        
+        assert_eq!(detect_protocol_version(&from_json(r#"{"jsonrpc":"2.0","id":1,"result":2}"#)), 
+            Some(ProtocolVersion::V2));
+        assert_eq!(detect_protocol_version(&from_json(r#"{"id":1,"result":2,"error":null}"#)), 
+            Some(ProtocolVersion::V1));
+        assert_eq!(detect_protocol_version(&from_json(r#"[{"jsonrpc":"2.0"}]"#)), None);
+        assert_eq!(detect_protocol_version(&from_json(r#""2.0""#)), None);
+    }
+    
+    #[test]
+    fn test_check_spec_violations() {
+        let violations = |json: &str| check_spec_violations(&from_json(json));
+        let no_violations : Vec<String> = vec![];
+        
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":1,"method":"foo","params":[]}"#), no_violations);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","method":"foo","trace":{}}"#), no_violations);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":null,"result":null}"#), no_violations);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":"x","error":{"code":-1,"message":"m","data":2}}"#), 
+            no_violations);
+        
+        assert_eq!(violations(r#"[]"#), vec!["Message is not an Object."]);
+        assert_eq!(violations(r#"{"id":1,"result":2}"#), vec!["Member `jsonrpc` is missing."]);
+        assert_eq!(violations(r#"{"jsonrpc":"1.0","id":1,"result":2}"#), vec![r#"Member `jsonrpc` is not exactly "2.0"."#]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0"}"#), 
+            vec!["Message is not a Request (no `method`), and as a Response has neither `result` nor `error`."]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":1,"method":"foo","result":1}"#), 
+            vec!["Message has both Request members (`method`) and Response members (`result`, `error`)."]);
+        
+        // Requests
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":[],"method":1,"params":null}"#), vec![
+            "Member `id` is not a String, Number, or Null.",
+            "Member `method` is not a String.",
+            "Member `params` is not an Object or Array.",
+        ]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","method":"rpc.foo"}"#), 
+            vec!["Method name `rpc.foo` is reserved for rpc-internal methods."]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":1,"method":"rpc.discover"}"#), no_violations);
+        
+        // Responses
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":1}"#), vec!["Response has neither `result` nor `error`."]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","result":1}"#), vec!["Member `id` is missing."]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":1,"result":1,"error":null}"#), vec![
+            "Response has both `result` and `error`.",
+            "Member `error` is not an Object.",
+        ]);
+        assert_eq!(violations(r#"{"jsonrpc":"2.0","id":1,"error":{"code":1.5}}"#), vec![
+            "Member `error.code` is not an Integer.",
+            "Member `error.message` is not a String.",
+        ]);
+    }
+    
+    #[test]
+    fn test_recover_message_id() {
+        assert_eq!(recover_message_id(&from_json(r#"{ "id": 12, "method": 1 }"#)), Id::Number(12));
+        assert_eq!(recover_message_id(&from_json(r#"{ "id": "abc" }"#)), Id::String("abc".into()));
+        assert_eq!(recover_message_id(&from_json(r#"{ "id": [] }"#)), Id::Null);
+        assert_eq!(recover_message_id(&from_json(r#"{ "method": "foo" }"#)), Id::Null);
+        assert_eq!(recover_message_id(&from_json(r#"[1, 2]"#)), Id::Null);
+    }
+    
+    #[test]
+    fn test_classify_message() {
+        assert_eq!(classify_message(&from_json(r#"{ "id": 1, "method": 1, "result": 2 }"#)), MessageKind::Request);
+        assert_eq!(classify_message(&from_json(r#"{ "id": 1, "error": 2 }"#)), MessageKind::Response);
+        assert_eq!(classify_message(&from_json(r#"{ "id": 1 }"#)), MessageKind::Unknown);
+        assert_eq!(classify_message(&from_json(r#"[1, 2]"#)), MessageKind::Unknown);
+    }
+    
+    #[test]
+    fn test_json_error_message() {
+        let value = from_json(r#"{ "jsonrpc": "2.0", "id": 7, "error": { "code": "x" } }"#);
+        let error = Message::from_value(value, ProtocolVersion::V2).unwrap_err();
+        // No location, not even of the nested Response error
+        assert_eq!(json_error_message(&error), "Value `\"x\"` of property `error.code` is not an Integer.");
+    }
+    
 }
\ No newline at end of file
@@ -17,7 +17,10 @@ use serde_json::Value;
 use util::core::GResult;
 
 use jsonrpc_common::*;
+use jsonrpc_message::MessageFormat;
+use jsonrpc_message::ProtocolVersion;
 use json_util::*;
+use json_path;
 
 /* -----------------  ----------------- */
 
@@ -42,56 +45,123 @@ pub struct Request {
     pub id : Option<Id>,
     pub method : String,
     pub params : RequestParams,
+    /// Members other than the ones defined by the spec. These are preserved when parsing,
+    /// and serialized after the standard members. Usually empty.
+    pub extensions : JsonObject,
 }
 
+/// The members defined by the spec for a Request. Extension members can't use these names.
+pub const REQUEST_MEMBERS : [&'static str; 4] = ["jsonrpc", "id", "method", "params"];
+
 impl Request {
     pub fn new(id_number: u64, method: String, params: JsonObject) -> Request {
         Request {
             id : Some(Id::Number(id_number)),
             method : method,
             params : RequestParams::Object(params),
+            extensions : new_object(),
         } 
     }
 }
 
-impl serde::Serialize for Request {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+impl Request {
+    pub fn serialize_with_format<S>(&self, serializer: &mut S, format: &MessageFormat) -> Result<(), S::Error>
         where S: serde::Serializer
     {
-        // TODO: need to investigate if elem_count = 4 is actually valid when id is missing
-        // serializing to JSON seems to not be a problem, but there might be other issues
-        let elem_count = 4;
-        let mut state = try!(serializer.serialize_struct("Request", elem_count)); 
+        if format.version == ProtocolVersion::V1 {
+            return self.serialize_v1(serializer);
+        }
+        
+        let omit_params = format.omit_none_params && self.params == RequestParams::None;
+        
+        let elem_count = 2 + (self.id.is_some() as usize) + (!omit_params as usize) 
+            + extensions_len(&self.extensions, &REQUEST_MEMBERS);
+        let mut state = try!(serializer.serialize_map(Some(elem_count))); 
         {
-            try!(serializer.serialize_struct_elt(&mut state, "jsonrpc", "2.0"));
+            try!(serialize_map_entry(serializer, &mut state, "jsonrpc", "2.0"));
             if let Some(ref id) = self.id {
-                try!(serializer.serialize_struct_elt(&mut state, "id", id));
+                try!(serialize_map_entry(serializer, &mut state, "id", id));
             }
-            try!(serializer.serialize_struct_elt(&mut state, "method", &self.method));
-            try!(serializer.serialize_struct_elt(&mut state, "params", &self.params));
+            try!(serialize_map_entry(serializer, &mut state, "method", &self.method));
+            if !omit_params {
+                try!(serialize_map_entry(serializer, &mut state, "params", &self.params));
+            }
+            try!(serialize_extensions(serializer, &mut state, &self.extensions, &REQUEST_MEMBERS));
         }
-        serializer.serialize_struct_end(state)
+        serializer.serialize_map_end(state)
     }
-}
-
-impl serde::Deserialize for Request {
-    fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+    
+    fn serialize_v1<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let elem_count = 3 + extensions_len(&self.extensions, &REQUEST_MEMBERS);
+        let mut state = try!(serializer.serialize_map(Some(elem_count))); 
+        {
+            // A notification has a `null` id
+            try!(serialize_map_entry(serializer, &mut state, "id", &self.id));
+            try!(serialize_map_entry(serializer, &mut state, "method", &self.method));
+            // params must be an Array
+            match self.params {
+                RequestParams::Array(ref array) => {
+                    try!(serialize_map_entry(serializer, &mut state, "params", array));
+                }
+                RequestParams::Object(ref object) => {
+                    try!(serialize_map_entry(serializer, &mut state, "params", &[object]));
+                }
+                RequestParams::None => {
+                    try!(serialize_map_entry(serializer, &mut state, "params", &[] as &[Value]));
+                }
+            }
+            try!(serialize_extensions(serializer, &mut state, &self.extensions, &REQUEST_MEMBERS));
+        }
+        serializer.serialize_map_end(state)
+    }
+    
+    pub fn from_value(value: Value, version: ProtocolVersion) -> Result<Self, serde_json::Error> {
+        Self::deserialize_with_version(&mut serde_json::value::Deserializer::new(value), version)
+    }
+    
+    pub fn deserialize_with_version<DE>(deserializer: &mut DE, version: ProtocolVersion) -> Result<Self, DE::Error>
         where DE: serde::Deserializer 
     {
         let mut helper = SerdeJsonDeserializerHelper(deserializer);
-        let value = try!(Value::deserialize(helper.0));
+        let value = try!(<Value as serde::Deserialize>::deserialize(helper.0));
         let mut json_obj = try!(helper.as_Object(value));
         
-        try!(check_jsonrpc_field(&mut helper, &mut json_obj));
+        if version == ProtocolVersion::V2 {
+            try!(check_jsonrpc_field(&mut helper, &mut json_obj));
+        } else {
+            json_obj.remove("jsonrpc");
+        }
         
+        // Note: a `null` id is read as a notification. That's as specified for 1.0, 
+        // and 2.0 discourages using `null` as an id. 
         let id = json_obj.remove("id");
-        let id = try!(id.map_or(Ok(None), |value| serde_json::from_value(value).map_err(to_de_error)));
+        let id = try!(id.map_or(Ok(None), |value| serde_json::from_value(value).map_err(json_to_de_error)));
         let method = try!(helper.obtain_String(&mut json_obj, "method"));
-        let params = try!(helper.obtain_Value(&mut json_obj, "params"));
+        // From the spec: `This member MAY be omitted.`
+        let params = json_obj.remove("params").unwrap_or(Value::Null);
         
         let params = try!(to_jsonrpc_params(params).map_err(to_de_error));
         
-        Ok(Request { id : id, method : method, params : params })
+        // Whatever remains are extension members
+        Ok(Request { id : id, method : method, params : params, extensions : json_obj })
+    }
+}
+
+impl serde::Serialize for Request {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        self.serialize_with_format(serializer, &MessageFormat::default())
+    }
+}
+
+impl serde::Deserialize for Request {
+    fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+        where DE: serde::Deserializer 
+    {
+        Self::deserialize_with_version(deserializer, ProtocolVersion::V2)
     }
 }
 
@@ -116,6 +186,49 @@ impl RequestParams {
             RequestParams::None => Value::Null,
         }
     }
+    
+    /// Map positional params onto named params, given the ordered names of the params.
+    /// 
+    /// Params missing from the end of the array are left out of the resulting object, 
+    /// so that optional params get their default. Object and none params are returned unchanged.
+    pub fn into_named<S: AsRef<str>>(self, param_names: &[S]) -> Result<RequestParams, String> {
+        let array = match self {
+            RequestParams::Array(array) => array,
+            params => return Ok(params),
+        };
+        if array.len() > param_names.len() {
+            return Err(format!("Expected at most {} positional params, got {}.", param_names.len(), array.len()));
+        }
+        
+        let mut object = new_object();
+        for (name, value) in param_names.iter().zip(array.into_iter()) {
+            object.insert(name.as_ref().to_string(), value);
+        }
+        Ok(RequestParams::Object(object))
+    }
+    
+    /// Map named params onto positional params, in the order of given param names. 
+    /// This is the reverse of `into_named`.
+    /// 
+    /// Params absent or null at the end are left out of the resulting array. 
+    /// Array and none params are returned unchanged.
+    pub fn into_positional<S: AsRef<str>>(self, param_names: &[S]) -> Result<RequestParams, String> {
+        let mut object = match self {
+            RequestParams::Object(object) => object,
+            params => return Ok(params),
+        };
+        
+        let mut array : Vec<Value> = param_names.iter()
+            .map(|name| object.remove(name.as_ref()).unwrap_or(Value::Null))
+            .collect();
+        if let Some(name) = object.keys().next() {
+            return Err(format!("Param `{}` has no position.", name));
+        }
+        while let Some(&Value::Null) = array.last() {
+            array.pop();
+        }
+        Ok(RequestParams::Array(array))
+    }
 }
 
 impl serde::Serialize for RequestParams {
@@ -139,6 +252,109 @@ pub fn to_jsonrpc_params(params: Value) -> GResult<RequestParams> {
     }
 }
 
+/* -----------------  RequestParams accessors  ----------------- */
+
+impl RequestParams {
+    
+    fn named_param(&self, name: &str) -> Result<Option<&Value>, RequestError> {
+        match *self {
+            RequestParams::Object(ref object) => Ok(object.get(name)),
+            RequestParams::None => Ok(None),
+            RequestParams::Array(_) => Err(error_JSON_RPC_InvalidParams_at("params", "Value is not an Object.")),
+        }
+    }
+    
+    /// Get the named param `name`, deserialized as a `T`. 
+    /// Fails with an Invalid Params error if the param is missing or not a valid `T`.
+    pub fn get<T: serde::Deserialize>(&self, name: &str) -> Result<T, RequestError> {
+        let path = json_path::member_path("params", name);
+        match try!(self.named_param(name)) {
+            Some(value) => from_param_value(value, &path),
+            None => Err(error_JSON_RPC_InvalidParams_at(&path, "Property is missing.")),
+        }
+    }
+    
+    /// Get the named param `name`, deserialized as a `T`, or `default` if the param is missing or null. 
+    pub fn get_or<T: serde::Deserialize>(&self, name: &str, default: T) -> Result<T, RequestError> {
+        match try!(self.named_param(name)) {
+            None | Some(&Value::Null) => Ok(default),
+            Some(value) => from_param_value(value, &json_path::member_path("params", name)),
+        }
+    }
+    
+    /// Get the positional param at `index`, deserialized as a `T`.
+    /// Fails with an Invalid Params error if the param is missing or not a valid `T`.
+    pub fn get_at<T: serde::Deserialize>(&self, index: usize) -> Result<T, RequestError> {
+        let path = json_path::index_path("params", index);
+        let array : &[Value] = match *self {
+            RequestParams::Array(ref array) => array,
+            RequestParams::None => &[],
+            RequestParams::Object(_) => return Err(error_JSON_RPC_InvalidParams_at("params", "Value is not an Array.")),
+        };
+        match array.get(index) {
+            Some(value) => from_param_value(value, &path),
+            None => Err(error_JSON_RPC_InvalidParams_at(&path, "Property is missing.")),
+        }
+    }
+    
+    /// Check that the named params have no members other than `known_names`. 
+    /// Fails with an Invalid Params error naming the first unknown param.
+    pub fn check_known_params(&self, known_names: &[&str]) -> Result<(), RequestError> {
+        if let RequestParams::Object(ref object) = *self {
+            if let Some(name) = object.keys().find(|name| !known_names.contains(&name.as_str())) {
+                return Err(error_JSON_RPC_InvalidParams_at(&json_path::member_path("params", name), 
+                    "Unknown param."));
+            }
+        }
+        Ok(())
+    }
+    
+}
+
+fn from_param_value<T: serde::Deserialize>(value: &Value, path: &str) -> Result<T, RequestError> {
+    json_path::from_value_at(value.clone(), path)
+        .map_err(|error| error_JSON_RPC_InvalidParams_at(&error.path, &error.message))
+}
+
+/// A builder of named params, for writing requests without defining a params struct.
+/// 
+/// Example: `ParamsBuilder::new().insert("line", 10).insert("uri", "file:///foo").build()`
+pub struct ParamsBuilder {
+    params : JsonObject,
+}
+
+impl ParamsBuilder {
+    pub fn new() -> ParamsBuilder {
+        ParamsBuilder { params : new_object() }
+    }
+    
+    /// Add the named param `name`, with given value serialized.
+    pub fn insert<T: serde::Serialize>(mut self, name: &str, value: T) -> ParamsBuilder {
+        self.params.insert(name.to_string(), serde_json::to_value(&value));
+        self
+    }
+    
+    /// Add the named param `name` only if `value` is present.
+    pub fn insert_opt<T: serde::Serialize>(self, name: &str, value: Option<T>) -> ParamsBuilder {
+        match value {
+            Some(value) => self.insert(name, value),
+            None => self,
+        }
+    }
+    
+    pub fn build(self) -> RequestParams {
+        RequestParams::Object(self.params)
+    }
+}
+
+/// Serialize given params as positional params, in the order of given param names.
+pub fn to_positional_params<PARAMS, S>(params: &PARAMS, param_names: &[S]) -> GResult<RequestParams> 
+    where PARAMS : serde::Serialize, S : AsRef<str>
+{
+    let params = try!(to_jsonrpc_params(serde_json::to_value(params)));
+    Ok(try!(params.into_positional(param_names)))
+}
+
 impl serde::Deserialize for RequestParams {
     fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
         where DE: serde::Deserializer 
@@ -185,6 +401,7 @@ pub mod request_tests {
     use json_util::*;
     use json_util::test_util::*;
     use jsonrpc_common::*;
+    use jsonrpc_message::*;
     
     use serde_json::Value;
     use serde_json::builder::ObjectBuilder;
@@ -201,6 +418,72 @@ pub mod request_tests {
         test_serde__RequestParams(RequestParams::None);
     }
     
+    #[test]
+    fn test__RequestParams_accessors() {
+        let params = ParamsBuilder::new()
+            .insert("line", 10)
+            .insert("uri", "file:///foo")
+            .insert("range", vec![1, 2])
+            .insert_opt("dry_run", None as Option<bool>)
+            .insert("flag", Value::Null)
+            .build();
+        assert_eq!(to_json(&params), r#"{"flag":null,"line":10,"range":[1,2],"uri":"file:///foo"}"#);
+        
+        assert_eq!(params.get::<u32>("line"), Ok(10));
+        assert_eq!(params.get::<String>("uri"), Ok("file:///foo".to_string()));
+        assert_eq!(params.get::<Vec<u8>>("range"), Ok(vec![1, 2]));
+        assert_eq!(params.get::<Option<bool>>("flag"), Ok(None));
+        assert_eq!(params.get::<u32>("column"), 
+            Err(error_JSON_RPC_InvalidParams_at("params.column", "Property is missing.")));
+        assert_eq!(params.get::<Vec<String>>("range").unwrap_err().data.unwrap().as_array().unwrap()[0].find("path"), 
+            Some(&Value::String("params.range[0]".into())));
+        
+        assert_eq!(params.get_or("column", 5), Ok(5));
+        assert_eq!(params.get_or("flag", true), Ok(true));
+        assert_eq!(params.get_or("line", 5), Ok(10));
+        assert_eq!(RequestParams::None.get_or("line", 5), Ok(5));
+        
+        assert_eq!(params.check_known_params(&["line", "uri", "range", "flag"]), Ok(()));
+        assert_eq!(params.check_known_params(&["line", "uri"]), 
+            Err(error_JSON_RPC_InvalidParams_at("params.flag", "Unknown param.")));
+        
+        let positional = RequestParams::Array(from_json(r#"[10, "foo"]"#));
+        assert_eq!(positional.get_at::<u32>(0), Ok(10));
+        assert_eq!(positional.get_at::<String>(1), Ok("foo".to_string()));
+        assert_eq!(positional.get_at::<u32>(2), 
+            Err(error_JSON_RPC_InvalidParams_at("params[2]", "Property is missing.")));
+        assert_eq!(positional.get::<u32>("line"), 
+            Err(error_JSON_RPC_InvalidParams_at("params", "Value is not an Object.")));
+        assert_eq!(params.get_at::<u32>(0), 
+            Err(error_JSON_RPC_InvalidParams_at("params", "Value is not an Array.")));
+    }
+    
+    #[test]
+    fn test__RequestParams_positional() {
+        let names = ["start", "end", "inclusive"];
+        let positional = |json: &str| RequestParams::Array(from_json(json));
+        let named = |json: &str| RequestParams::Object(unwrap_object(from_json(json)));
+        
+        assert_eq!(positional("[1, 2, true]").into_named(&names), Ok(named(r#"{"start":1,"end":2,"inclusive":true}"#)));
+        assert_eq!(positional("[1, null]").into_named(&names), Ok(named(r#"{"start":1,"end":null}"#)));
+        assert_eq!(positional("[]").into_named(&names), Ok(named(r#"{}"#)));
+        assert_eq!(positional("[1, 2, 3, 4]").into_named(&names), 
+            Err("Expected at most 3 positional params, got 4.".to_string()));
+        assert_eq!(named(r#"{"end":1}"#).into_named(&names), Ok(named(r#"{"end":1}"#)));
+        assert_eq!(RequestParams::None.into_named(&names), Ok(RequestParams::None));
+        
+        assert_eq!(named(r#"{"start":1,"end":2,"inclusive":true}"#).into_positional(&names), 
+            Ok(positional("[1, 2, true]")));
+        assert_eq!(named(r#"{"end":2,"inclusive":null}"#).into_positional(&names), Ok(positional("[null, 2]")));
+        assert_eq!(named(r#"{"start":1,"foo":2}"#).into_positional(&names), 
+            Err("Param `foo` has no position.".to_string()));
+        assert_eq!(positional("[1]").into_positional(&names), Ok(positional("[1]")));
+        
+        let params = to_positional_params(&named(r#"{"end":2,"start":1}"#), &names).unwrap();
+        assert_eq!(params, positional("[1, 2]"));
+        assert_eq!(params.into_named(&names), Ok(named(r#"{"start":1,"end":2}"#)));
+    }
+    
     fn test_serde__RequestParams(params: RequestParams) {
         let params_reser = test_serde(&params).0;
         assert_equal(params_reser, params);
@@ -241,18 +524,23 @@ pub mod request_tests {
         );
         test_error_de::<Request>(
             r#"{ "jsonrpc": "2.0", "method":null }"#,
-            "Value `null` is not a String.",
+            "Value `null` of property `method` is not a String.",
         );
         
         test_error_de::<Request>(
-            r#"{ "jsonrpc": "2.0", "method":"xxx" }"#,
-            "Property `params` is missing.",
+            r#"{ "jsonrpc": "2.0", "method":"xxx", "params":123 }"#,
+            "Property `params` not an Object, Array, or null.",
         );
         
         // Test valid request with params = null
         assert_equal(
             from_json(r#"{ "jsonrpc": "2.0", "method":"xxx", "params":null }"#),
-            Request { id : None, method : "xxx".into(), params : RequestParams::None, } 
+            Request { id : None, method : "xxx".into(), params : RequestParams::None, extensions : new_object() } 
+        );
+        // Test valid request with params omitted
+        assert_equal(
+            from_json(r#"{ "jsonrpc": "2.0", "id":1, "method":"xxx" }"#),
+            Request { id : Some(Id::Number(1)), method : "xxx".into(), params : RequestParams::None, extensions : new_object() } 
         );
         
         // --- Test serialization ---
@@ -262,13 +550,56 @@ pub mod request_tests {
         test_serde(&request);
         
         // Test basic Request, no params
-        let request = Request { id : None, method : "myMethod".to_string(), params : RequestParams::None, };
+        let request = Request { id : None, method : "myMethod".to_string(), params : RequestParams::None, extensions : new_object() };
         test_serde(&request);
         
         // Test Request with no id
         let sample_array_params = RequestParams::Array(vec![]);
-        let request = Request { id : None, method : "myMethod".to_string(), params : sample_array_params, };  
+        let request = Request { id : None, method : "myMethod".to_string(), params : sample_array_params, extensions : new_object() };  
         test_serde(&request);
+        
+        // Test MessageFormat with omitted params
+        let format = MessageFormat { omit_none_params : true, .. MessageFormat::default() };
+        let request = Request { id : Some(Id::Number(1)), method : "myMethod".to_string(), params : RequestParams::None, 
+            extensions : new_object() };
+        let message = Message::Request(request.clone());
+        assert_equal(to_json(&FormattedMessage(&message, &format)), 
+            r#"{"jsonrpc":"2.0","id":1,"method":"myMethod"}"#.to_string());
+        assert_equal(from_json::<Request>(&to_json(&FormattedMessage(&message, &format))), request.clone());
+        assert_equal(to_json(&request), r#"{"jsonrpc":"2.0","id":1,"method":"myMethod","params":null}"#.to_string());
+        
+        let request = Request { id : None, method : "myMethod".to_string(), params : RequestParams::Array(vec![]), 
+            extensions : new_object() };
+        let message = Message::Request(request);
+        assert_equal(to_json(&FormattedMessage(&message, &format)), 
+            r#"{"jsonrpc":"2.0","method":"myMethod","params":[]}"#.to_string());
+    }
+    
+    #[test]
+    fn test_Request_extensions() {
+        let json = r#"{"jsonrpc":"2.0","id":1,"method":"myMethod","params":null,"meta":{"trace":"abc"},"x":1}"#;
+        let request = from_json::<Request>(json);
+        
+        let mut extensions = new_object();
+        extensions.insert("meta".into(), from_json(r#"{"trace":"abc"}"#));
+        extensions.insert("x".into(), Value::U64(1));
+        assert_equal(&request.extensions, &extensions);
+        assert_equal(to_json(&request), json.to_string());
+        test_serde(&request);
+        
+        // Extensions clashing with standard members are not serialized
+        let mut request = request;
+        request.extensions = new_object();
+        request.extensions.insert("method".into(), Value::U64(1));
+        assert_equal(to_json(&request), r#"{"jsonrpc":"2.0","id":1,"method":"myMethod","params":null}"#.to_string());
+        
+        // 1.0
+        let request = Request::from_value(from_json(r#"{"id":1,"method":"m","params":[],"meta":2}"#), 
+            ProtocolVersion::V1).unwrap();
+        assert_equal(request.extensions.get("meta"), Some(&Value::U64(2)));
+        let format = MessageFormat { version : ProtocolVersion::V1, .. MessageFormat::default() };
+        assert_equal(to_json(&FormattedMessage(&request.into(), &format)), 
+            r#"{"id":1,"method":"m","params":[],"meta":2}"#.to_string());
     }
     
 }
\ No newline at end of file
@@ -12,6 +12,8 @@ use serde_json::Value;
 
 use jsonrpc_common::*;
 use jsonrpc_request::check_jsonrpc_field;
+use jsonrpc_message::MessageFormat;
+use jsonrpc_message::ProtocolVersion;
 use json_util::*;
 
 
@@ -25,15 +27,25 @@ pub struct Response {
     pub id : Id, 
     // field `result` or field `error`:
     pub result_or_error: ResponseResult,
+    /// Members other than the ones defined by the spec. These are preserved when parsing,
+    /// and serialized after the standard members. Usually empty.
+    pub extensions : JsonObject,
 }
 
+/// The members defined by the spec for a Response. Extension members can't use these names.
+pub const RESPONSE_MEMBERS : [&'static str; 4] = ["jsonrpc", "id", "result", "error"];
+
 impl Response {
+    pub fn new(id: Id, result_or_error: ResponseResult) -> Response {
+        Response { id : id, result_or_error : result_or_error, extensions : new_object() }
+    }
+    
     pub fn new_result(id: Id, result: Value) -> Response {
-        Response { id : id, result_or_error : ResponseResult::Result(result) }
+        Response::new(id, ResponseResult::Result(result))
     }
     
     pub fn new_error(id: Id, error: RequestError) -> Response {
-        Response { id : id, result_or_error : ResponseResult::Error(error) }
+        Response::new(id, ResponseResult::Error(error))
     }
 }
 
@@ -54,60 +66,122 @@ impl ResponseResult {
     }
     
     pub fn new_error(id: Id, error: RequestError) -> Response {
-        Response { id : id, result_or_error : ResponseResult::Error(error) }
+        Response::new_error(id, error)
     }
 }
 
 
-impl serde::Serialize for Response {
-    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+impl Response {
+    pub fn serialize_with_format<S>(&self, serializer: &mut S, format: &MessageFormat) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        if format.version == ProtocolVersion::V1 {
+            return self.serialize_v1(serializer);
+        }
+        serde::Serialize::serialize(self, serializer)
+    }
+    
+    fn serialize_v1<S>(&self, serializer: &mut S) -> Result<(), S::Error>
         where S: serde::Serializer
     {
-        let elem_count = 3;
-        let mut state = try!(serializer.serialize_struct("Response", elem_count));
+        let elem_count = 3 + extensions_len(&self.extensions, &RESPONSE_MEMBERS);
+        let mut state = try!(serializer.serialize_map(Some(elem_count)));
         {
-            try!(serializer.serialize_struct_elt(&mut state, "jsonrpc", "2.0"));
-            try!(serializer.serialize_struct_elt(&mut state, "id", &self.id));
+            try!(serialize_map_entry(serializer, &mut state, "id", &self.id));
             
+            // Both `result` and `error` are present, one of them null
             match self.result_or_error {
                 ResponseResult::Result(ref value) => {
-                    try!(serializer.serialize_struct_elt(&mut state, "result", &value));
+                    try!(serialize_map_entry(serializer, &mut state, "result", &value));
+                    try!(serialize_map_entry(serializer, &mut state, "error", &Value::Null));
                 }
                 ResponseResult::Error(ref json_rpc_error) => {
-                    try!(serializer.serialize_struct_elt(&mut state, "error", &json_rpc_error)); 
+                    try!(serialize_map_entry(serializer, &mut state, "result", &Value::Null));
+                    try!(serialize_map_entry(serializer, &mut state, "error", &json_rpc_error)); 
                 }
             }
+            try!(serialize_extensions(serializer, &mut state, &self.extensions, &RESPONSE_MEMBERS));
         }
-        serializer.serialize_struct_end(state)
+        serializer.serialize_map_end(state)
     }
-}
-
-impl serde::Deserialize for Response {
-    fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+    
+    pub fn from_value(value: Value, version: ProtocolVersion) -> Result<Self, serde_json::Error> {
+        Self::deserialize_with_version(&mut serde_json::value::Deserializer::new(value), version)
+    }
+    
+    pub fn deserialize_with_version<DE>(deserializer: &mut DE, version: ProtocolVersion) -> Result<Self, DE::Error>
         where DE: serde::Deserializer 
     {
         let mut helper = SerdeJsonDeserializerHelper(deserializer);
-        let value = try!(Value::deserialize(helper.0));
+        let value = try!(<Value as serde::Deserialize>::deserialize(helper.0));
         let mut json_obj = try!(helper.as_Object(value));
         
-        try!(check_jsonrpc_field(&mut helper, &mut json_obj));
+        if version == ProtocolVersion::V2 {
+            try!(check_jsonrpc_field(&mut helper, &mut json_obj));
+        } else {
+            json_obj.remove("jsonrpc");
+        }
         
         let id_value = try!(helper.obtain_Value(&mut json_obj, "id"));
-        let id : Id = try!(serde_json::from_value(id_value).map_err(to_de_error));
+        let id : Id = try!(serde_json::from_value(id_value).map_err(json_to_de_error));
         
+        if version == ProtocolVersion::V1 {
+            // In 1.0 both members are present, the unused one being `null`
+            if let Some(&Value::Null) = json_obj.get("error") {
+                json_obj.remove("error");
+            } else if let Some(&Value::Null) = json_obj.get("result") {
+                json_obj.remove("result");
+            }
+        }
+        
+        let result = json_obj.remove("result");
+        let error = json_obj.remove("error");
         let result_or_error : ResponseResult = {
-            if let Some(result) = json_obj.remove("result") {
+            if let Some(result) = result {
                 ResponseResult::Result(result)
             } else  
-            if let Some(error_obj) = json_obj.remove("error") {
-                let error : RequestError = try!(serde_json::from_value(error_obj).map_err(to_de_error));
+            if let Some(error_obj) = error {
+                let error = try!(RequestError::from_value_with(&mut helper.at_member("error"), error_obj));
                 ResponseResult::Error(error)
             } else {
                 return Err(new_de_error("Missing property `result` or `error`".to_string()));
             }
         };
         
-        Ok(Response{ id : id, result_or_error : result_or_error }) 
+        // Whatever remains are extension members
+        Ok(Response{ id : id, result_or_error : result_or_error, extensions : json_obj }) 
+    }
+}
+
+impl serde::Serialize for Response {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: serde::Serializer
+    {
+        let elem_count = 3 + extensions_len(&self.extensions, &RESPONSE_MEMBERS);
+        let mut state = try!(serializer.serialize_map(Some(elem_count)));
+        {
+            try!(serialize_map_entry(serializer, &mut state, "jsonrpc", "2.0"));
+            try!(serialize_map_entry(serializer, &mut state, "id", &self.id));
+            
+            match self.result_or_error {
+                ResponseResult::Result(ref value) => {
+                    try!(serialize_map_entry(serializer, &mut state, "result", &value));
+                }
+                ResponseResult::Error(ref json_rpc_error) => {
+                    try!(serialize_map_entry(serializer, &mut state, "error", &json_rpc_error)); 
+                }
+            }
+            try!(serialize_extensions(serializer, &mut state, &self.extensions, &RESPONSE_MEMBERS));
+        }
+        serializer.serialize_map_end(state)
+    }
+}
+
+impl serde::Deserialize for Response {
+    fn deserialize<DE>(deserializer: &mut DE) -> Result<Self, DE::Error>
+        where DE: serde::Deserializer 
+    {
+        Self::deserialize_with_version(deserializer, ProtocolVersion::V2)
     }
 }
 
@@ -147,6 +221,14 @@ pub mod response_tests {
             r#"{ "jsonrpc":"2.0", "id":123 }"#, 
             "Missing property `result` or `error`"
         );
+        test_error_de::<Response>(
+            r#"{ "jsonrpc":"2.0", "id":123, "error":{ "code":1 } }"#, 
+            "Property `error.message` is missing."
+        );
+        test_error_de::<Response>(
+            r#"{ "jsonrpc":"2.0", "id":123, "error":{ "code":"1", "message":"" } }"#, 
+            r#"Value `"1"` of property `error.code` is not an Integer."#
+        );
 
         
         let response = Response::new_result(Id::Null, sample_json_obj(100));
@@ -188,4 +270,21 @@ pub mod response_tests {
         );
         
     }
+    
+    #[test]
+    fn test_Response_extensions() {
+        let json = r#"{"jsonrpc":"2.0","id":1,"result":null,"meta":{"trace":"abc"}}"#;
+        let response = from_json::<Response>(json);
+        assert_eq!(response.result_or_error, ResponseResult::Result(Value::Null));
+        assert_eq!(response.extensions.get("meta"), Some(&from_json(r#"{"trace":"abc"}"#)));
+        assert_eq!(to_json(&response), json);
+        
+        // `error` is never an extension, even if `result` is present
+        let response = from_json::<Response>(r#"{"jsonrpc":"2.0","id":1,"result":2,"error":3}"#);
+        assert_eq!(response, Response::new_result(Id::Number(1), Value::U64(2)));
+        
+        let response = Response::from_value(from_json(r#"{"id":1,"result":2,"error":null,"x":1}"#), 
+            ProtocolVersion::V1).unwrap();
+        assert_eq!(response.extensions.get("x"), Some(&Value::U64(1)));
+    }
 }
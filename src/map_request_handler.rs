@@ -8,28 +8,117 @@
 use util::core::*;
 
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
 
 use super::ResponseCompletable;
 use super::RequestHandler;
 use super::serde;
 
+use serde_json;
+use serde_json::Value;
+
 use method_types::*;
+use json_util::*;
+use json_schema::*;
 use jsonrpc_common::*;
 use jsonrpc_request::*;
+use jsonrpc_response::*;
 
 
 /* -----------------  MapRequestHandler  ----------------- */
 
 pub type RpcMethodHandler = Fn(RequestParams, ResponseCompletable);
 
+/// A RequestHandler that dispatches requests to the handler registered for the method name.
+/// 
+/// Method names starting with `rpc.` are reserved by the spec: registering one fails with 
+/// `AddMethodError::ReservedName`.
+/// Instead, the rpc-internal method `rpc.discover` is provided, 
+/// returning an OpenRPC description of the registered methods.
 pub struct MapRequestHandler {
     pub method_handlers : HashMap<String, Box<RpcMethodHandler>>,
+    pub method_descriptions : HashMap<String, MethodDescription>,
+    /// The title of the service, for `rpc.discover`.
+    pub title : String,
+    /// The version of the service, for `rpc.discover`.
+    pub version : String,
+}
+
+/// The error of registering a method in a MapRequestHandler.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AddMethodError {
+    /// The method name is reserved for rpc-internal methods (it starts with `rpc.`).
+    ReservedName(String),
+}
+
+impl fmt::Display for AddMethodError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AddMethodError::ReservedName(ref method_name) => 
+                write!(fmt, "Method name `{}` is reserved for rpc-internal methods.", method_name),
+        }
+    }
+}
+
+impl error::Error for AddMethodError {
+}
+
+/// A description of a registered method, for `rpc.discover`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MethodDescription {
+    pub is_notification : bool,
+    /// The JSON Schema of the params, if known.
+    pub params_schema : Option<Value>,
+    /// The JSON Schema of the result, if known. Not applicable to notifications.
+    pub result_schema : Option<Value>,
+    /// The JSON Schema of the error data, if known. Not applicable to notifications.
+    pub error_data_schema : Option<Value>,
+    /// If true, incoming params are validated against `params_schema` before the handler is invoked.
+    /// Invalid params are rejected with an Invalid Params error listing the violations.
+    pub validate_params : bool,
+    /// The names of the params, in positional order, if the method accepts positional params.
+    /// Positional (array) params are then mapped onto named params before the handler is invoked.
+    pub param_names : Option<Vec<String>>,
+}
+
+impl MethodDescription {
+    /// The description of a request with given param, result, and error data types.
+    pub fn for_request<PARAMS, RET, RET_ERROR>() -> MethodDescription 
+    where 
+        PARAMS : JsonSchema, 
+        RET : JsonSchema, 
+        RET_ERROR : JsonSchema,
+    {
+        MethodDescription {
+            is_notification : false,
+            params_schema : Some(PARAMS::json_schema()),
+            result_schema : Some(RET::json_schema()),
+            error_data_schema : Some(RET_ERROR::json_schema()),
+            validate_params : false,
+            param_names : None,
+        }
+    }
+    
+    /// The description of a notification with given param type.
+    pub fn for_notification<PARAMS : JsonSchema>() -> MethodDescription {
+        MethodDescription {
+            is_notification : true,
+            params_schema : Some(PARAMS::json_schema()),
+            .. MethodDescription::default()
+        }
+    }
 }
 
 impl MapRequestHandler {
     
     pub fn new() -> MapRequestHandler {
-         MapRequestHandler { method_handlers : HashMap::new() }
+         MapRequestHandler { 
+             method_handlers : HashMap::new(), 
+             method_descriptions : HashMap::new(),
+             title : "".into(),
+             version : "".into(),
+        }
     }
     
     pub fn add_notification<
@@ -38,11 +127,41 @@ impl MapRequestHandler {
         &mut self,
         method_name: &'static str, 
         method_fn: Box<Fn(PARAMS)>
-    ) {
+    ) -> Result<(), AddMethodError> {
         let req_handler : Box<RpcMethodHandler> = new(move |params, completable| {
             completable.sync_handle_notification(params, &*method_fn);
         });
-        self.add_rpc_handler(method_name, req_handler);
+        let description = MethodDescription { is_notification : true, .. MethodDescription::default() };
+        self.add_described_rpc_handler(method_name, req_handler, description)
+    }
+    
+    /// Same as `add_notification`, but also records the params schema, for `rpc.discover`.
+    pub fn add_notification_with_schema<
+        PARAMS : serde::Deserialize + JsonSchema + 'static,
+    >(
+        &mut self,
+        method_name: &'static str, 
+        method_fn: Box<Fn(PARAMS)>
+    ) -> Result<(), AddMethodError> {
+        try!(self.add_notification(method_name, method_fn));
+        self.method_descriptions.insert(method_name.to_string(), MethodDescription::for_notification::<PARAMS>());
+        Ok(())
+    }
+    
+    /// Same as `add_notification_with_schema`, but also validates incoming params against the params schema.
+    pub fn add_validated_notification<
+        PARAMS : serde::Deserialize + JsonSchema + 'static,
+    >(
+        &mut self,
+        method_name: &'static str, 
+        method_fn: Box<Fn(PARAMS)>
+    ) -> Result<(), AddMethodError> {
+        try!(self.add_notification(method_name, method_fn));
+        self.method_descriptions.insert(method_name.to_string(), MethodDescription { 
+            validate_params : true, 
+            .. MethodDescription::for_notification::<PARAMS>()
+        });
+        Ok(())
     }
     
     pub fn add_request<
@@ -53,19 +172,145 @@ impl MapRequestHandler {
         &mut self,
         method_name: &'static str, 
         method_fn: Box<Fn(PARAMS) -> MethodResult<RET, RET_ERROR>>
-    ) {
+    ) -> Result<(), AddMethodError> {
         let req_handler : Box<RpcMethodHandler> = new(move |params, completable| {
             completable.sync_handle_request(params, &*method_fn);
         });
-        self.add_rpc_handler(method_name, req_handler);
+        self.add_rpc_handler(method_name, req_handler)
+    }
+    
+    /// Same as `add_request`, but also records the params, result, and error data schemas, 
+    /// for `rpc.discover`.
+    pub fn add_request_with_schema<
+        PARAMS : serde::Deserialize + JsonSchema + 'static, 
+        RET : serde::Serialize + JsonSchema + 'static, 
+        RET_ERROR : serde::Serialize + JsonSchema + 'static
+    >(
+        &mut self,
+        method_name: &'static str, 
+        method_fn: Box<Fn(PARAMS) -> MethodResult<RET, RET_ERROR>>
+    ) -> Result<(), AddMethodError> {
+        try!(self.add_request(method_name, method_fn));
+        self.method_descriptions.insert(method_name.to_string(), 
+            MethodDescription::for_request::<PARAMS, RET, RET_ERROR>());
+        Ok(())
+    }
+    
+    /// Same as `add_request_with_schema`, but also validates incoming params against the params schema.
+    pub fn add_validated_request<
+        PARAMS : serde::Deserialize + JsonSchema + 'static, 
+        RET : serde::Serialize + JsonSchema + 'static, 
+        RET_ERROR : serde::Serialize + JsonSchema + 'static
+    >(
+        &mut self,
+        method_name: &'static str, 
+        method_fn: Box<Fn(PARAMS) -> MethodResult<RET, RET_ERROR>>
+    ) -> Result<(), AddMethodError> {
+        try!(self.add_request(method_name, method_fn));
+        self.method_descriptions.insert(method_name.to_string(), MethodDescription { 
+            validate_params : true, 
+            .. MethodDescription::for_request::<PARAMS, RET, RET_ERROR>()
+        });
+        Ok(())
     }
     
+    /// Register given handler for a (non-notification) method.
+    /// 
+    /// Fails if method_name is reserved (starts with `rpc.`).
     pub fn add_rpc_handler(
         &mut self,
         method_name: &'static str,
         method_handler: Box<RpcMethodHandler>
-    ) {
+    ) -> Result<(), AddMethodError> {
+        self.add_described_rpc_handler(method_name, method_handler, MethodDescription::default())
+    }
+    
+    /// Register given handler, with given description.
+    /// 
+    /// Fails if method_name is reserved (starts with `rpc.`).
+    pub fn add_described_rpc_handler(
+        &mut self,
+        method_name: &'static str,
+        method_handler: Box<RpcMethodHandler>,
+        description: MethodDescription,
+    ) -> Result<(), AddMethodError> {
+        if is_reserved_method_name(method_name) {
+            return Err(AddMethodError::ReservedName(method_name.to_string()));
+        }
         self.method_handlers.insert(method_name.to_string(), method_handler);
+        self.method_descriptions.insert(method_name.to_string(), description);
+        Ok(())
+    }
+    
+    /// Declare the names of the params of given method, in positional order, 
+    /// so that positional params are accepted for that method. 
+    /// Trailing params can be omitted, in which case they are absent from the named params.
+    /// 
+    /// Panics if the method is not registered.
+    pub fn set_param_names(&mut self, method_name: &str, param_names: &[&str]) {
+        if !self.method_handlers.contains_key(method_name) {
+            panic!("Method `{}` is not registered.", method_name);
+        }
+        let description = self.method_descriptions.entry(method_name.to_string()).or_insert_with(Default::default);
+        description.param_names = Some(param_names.iter().map(|name| name.to_string()).collect());
+    }
+    
+    /// Describe the registered methods, as an OpenRPC document. This is the result of `rpc.discover`.
+    pub fn describe(&self) -> Value {
+        let mut method_names : Vec<&String> = self.method_handlers.keys().collect();
+        method_names.sort();
+        
+        let methods = method_names.into_iter().map(|method_name| {
+            let default_description = MethodDescription::default();
+            let description = self.method_descriptions.get(method_name).unwrap_or(&default_description);
+            describe_method(method_name, description)
+        }).collect();
+        
+        let mut info = new_object();
+        info.insert("title".into(), Value::String(self.title.clone()));
+        info.insert("version".into(), Value::String(self.version.clone()));
+        
+        let mut document = new_object();
+        document.insert("openrpc".into(), Value::String(OPENRPC_VERSION.into()));
+        document.insert("info".into(), Value::Object(info));
+        document.insert("methods".into(), Value::Array(methods));
+        Value::Object(document)
+    }
+    
+    /// The OpenRPC document describing the registered methods, as JSON text.
+    pub fn to_openrpc_json(&self) -> String {
+        serde_json::to_string_pretty(&self.describe()).expect("Failed to serialize Value")
+    }
+    
+    /// Validate given params against the params schema of given method, if validation is enabled for it.
+    pub fn validate_params(&self, method_name: &str, request_params: &RequestParams) -> Result<(), RequestError> {
+        let description = match self.method_descriptions.get(method_name) {
+            Some(description) if description.validate_params => description,
+            _ => return Ok(()),
+        };
+        let schema = match description.params_schema {
+            Some(ref schema) => schema,
+            None => return Ok(()),
+        };
+        
+        let violations = validate_with_root(schema, &request_params.clone().into_value(), "params");
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(error_JSON_RPC_InvalidParams_for(&violations))
+        }
+    }
+    
+    /// Map positional params onto named params, if given method declares param names.
+    pub fn to_named_params(&self, method_name: &str, request_params: RequestParams) 
+        -> Result<RequestParams, RequestError> 
+    {
+        let param_names = self.method_descriptions.get(method_name)
+            .and_then(|description| description.param_names.as_ref());
+        match param_names {
+            Some(param_names) => request_params.into_named(param_names).map_err(error_JSON_RPC_InvalidParams),
+            None => Ok(request_params),
+        }
     }
     
     fn do_invoke_method(
@@ -74,8 +319,22 @@ impl MapRequestHandler {
         completable: ResponseCompletable,
         request_params: RequestParams,
     ) {
+        if method_name == RPC_DISCOVER_METHOD {
+            completable.complete(Some(ResponseResult::Result(self.describe())));
+        } else 
         if let Some(method_fn) = self.method_handlers.get(method_name) 
         {
+            let request_params = match self.to_named_params(method_name, request_params) {
+                Ok(request_params) => request_params,
+                Err(error) => {
+                    completable.complete_with_error(error);
+                    return;
+                }
+            };
+            if let Err(error) = self.validate_params(method_name, &request_params) {
+                completable.complete_with_error(error);
+                return;
+            }
             let method_fn : &Box<RpcMethodHandler> = method_fn;
             method_fn(request_params, completable);
         } else {
@@ -94,3 +353,78 @@ impl RequestHandler for MapRequestHandler {
     }
     
 }
+
+/* -----------------  OpenRPC  ----------------- */
+
+pub const OPENRPC_VERSION : &'static str = "1.2.6";
+
+/// Describe a method as an OpenRPC Method Object. 
+/// Notifications are marked with the `x-notification` extension, 
+/// and the error data schema is given by the `x-error-data` extension.
+/// 
+/// If the params schema is an object schema, each of its properties is described as a by-name param.
+/// If the method declares param names, its params are listed in that order, 
+/// and it's marked as accepting both by-name and by-position params.
+/// A null schema is described as no params.
+/// Otherwise the params are described as a single param, named `params`.
+pub fn describe_method(method_name: &str, description: &MethodDescription) -> Value {
+    let mut method = new_object();
+    method.insert("name".into(), Value::String(method_name.into()));
+    method.insert("x-notification".into(), Value::Bool(description.is_notification));
+    
+    let mut params = match description.params_schema {
+        Some(ref schema) => describe_params(schema),
+        None => vec![],
+    };
+    if let Some(ref param_names) = description.param_names {
+        // Params not named (unexpected) go last
+        params.sort_by_key(|param| {
+            let name = param.find("name").and_then(Value::as_str);
+            param_names.iter().position(|param_name| Some(param_name.as_str()) == name).unwrap_or(param_names.len())
+        });
+        method.insert("paramStructure".into(), Value::String("either".into()));
+    }
+    method.insert("params".into(), Value::Array(params));
+    
+    if !description.is_notification {
+        // From OpenRPC: an absent schema means the result is unknown, which the empty schema expresses
+        let schema = description.result_schema.clone().unwrap_or_else(|| Value::Object(new_object()));
+        method.insert("result".into(), content_descriptor("result", schema, None));
+        
+        if let Some(ref error_data_schema) = description.error_data_schema {
+            if !is_null_schema(error_data_schema) {
+                method.insert("x-error-data".into(), error_data_schema.clone());
+            }
+        }
+    }
+    Value::Object(method)
+}
+
+fn describe_params(schema: &Value) -> Vec<Value> {
+    if is_null_schema(schema) {
+        return vec![];
+    }
+    let properties = schema.find("properties").and_then(Value::as_object);
+    let properties = match properties {
+        Some(properties) => properties,
+        None => return vec![content_descriptor("params", schema.clone(), Some(true))],
+    };
+    
+    let required : Vec<&str> = schema.find("required").and_then(Value::as_array)
+        .map(|required| required.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default();
+    
+    properties.iter().map(|(name, property_schema)| {
+        content_descriptor(name, property_schema.clone(), Some(required.contains(&name.as_str())))
+    }).collect()
+}
+
+fn content_descriptor(name: &str, schema: Value, required: Option<bool>) -> Value {
+    let mut descriptor = new_object();
+    descriptor.insert("name".into(), Value::String(name.into()));
+    if let Some(required) = required {
+        descriptor.insert("required".into(), Value::Bool(required));
+    }
+    descriptor.insert("schema".into(), schema);
+    Value::Object(descriptor)
+}
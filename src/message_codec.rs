@@ -0,0 +1,803 @@
+// Copyright 2016 Bruno Medeiros
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0>.
+// This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/*!
+
+Codecs for encoding and decoding JSON-RPC messages to and from bytes.
+
+JSON is the default wire format, but the MessagePack and CBOR codecs allow keeping
+JSON-RPC semantics with a cheaper binary encoding.
+All codecs work on the JSON data model (`serde_json::Value`), so `Request`, `Response`,
+`Id` and `RequestError` round-trip identically in every codec.
+
+Binary codecs should be used with a length-prefixed framing,
+see `service_util::LengthPrefixedMessageReader` and `service_util::LengthPrefixedMessageWriter`.
+
+*/
+
+use std::collections::BTreeMap;
+
+use serde_json;
+use serde_json::Value;
+
+use util::core::*;
+
+use json_util::JsonObject;
+use jsonrpc_message::Message;
+use jsonrpc_message::MessageFormat;
+use jsonrpc_message::FormattedMessage;
+
+
+/* -----------------  MessageCodec  ----------------- */
+
+pub trait MessageCodec : Send + Sync {
+
+    /// Encode given JSON value into bytes.
+    fn encode_value(&self, value: &Value) -> GResult<Vec<u8>>;
+
+    /// Decode bytes into a JSON value.
+    /// An error here means the bytes are not well-formed in this codec's format.
+    fn decode_value(&self, bytes: &[u8]) -> GResult<Value>;
+
+    fn encode_message(&self, message: &Message, format: &MessageFormat) -> GResult<Vec<u8>> {
+        self.encode_value(&serde_json::to_value(&FormattedMessage(message, format)))
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> GResult<Message> {
+        let value = try!(self.decode_value(bytes));
+        Ok(try!(serde_json::from_value::<Message>(value)))
+    }
+}
+
+/// The default codec: messages are encoded as UTF-8 JSON text.
+pub struct JsonCodec;
+
+impl MessageCodec for JsonCodec {
+
+    fn encode_value(&self, value: &Value) -> GResult<Vec<u8>> {
+        Ok(try!(serde_json::to_vec(value)))
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> GResult<Value> {
+        Ok(try!(serde_json::from_slice::<Value>(bytes)))
+    }
+
+    fn encode_message(&self, message: &Message, format: &MessageFormat) -> GResult<Vec<u8>> {
+        Ok(try!(serde_json::to_vec(&FormattedMessage(message, format))))
+    }
+
+    fn decode_message(&self, bytes: &[u8]) -> GResult<Message> {
+        Ok(try!(serde_json::from_slice::<Message>(bytes)))
+    }
+}
+
+
+/* -----------------  MessageLimits  ----------------- */
+
+/// Limits applied to incoming messages, to protect against misbehaving peers.
+/// 
+/// The Endpoint's limits are also applied to the message reader of its read loop 
+/// (see `MessageReader::apply_limits`), so that an oversized message is rejected while it's read.
+/// 
+/// Note that nesting depth can't be raised above `DECODE_DEPTH_LIMIT`,
+/// which the decoders (including serde_json) enforce regardless.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageLimits {
+    /// Maximum size, in bytes, of an encoded message.
+    pub max_message_size : usize,
+    /// Maximum nesting depth of arrays and objects. The top-level message object has depth 1.
+    pub max_depth : usize,
+    pub max_array_len : usize,
+    pub max_object_len : usize,
+    /// Maximum length, in bytes, of any string, including object keys.
+    pub max_string_len : usize,
+}
+
+impl MessageLimits {
+    
+    pub fn unlimited() -> MessageLimits {
+        MessageLimits {
+            max_message_size : usize::max_value(),
+            max_depth : DECODE_DEPTH_LIMIT,
+            max_array_len : usize::max_value(),
+            max_object_len : usize::max_value(),
+            max_string_len : usize::max_value(),
+        }
+    }
+    
+    pub fn check_message_size(&self, size: usize) -> Result<(), String> {
+        if size > self.max_message_size {
+            return Err(format!("Message size {} exceeds limit of {} bytes.", size, self.max_message_size));
+        }
+        Ok(())
+    }
+    
+    /// Check given decoded value is within these limits.
+    pub fn check_value(&self, value: &Value) -> Result<(), String> {
+        self.do_check_value(value, 1)
+    }
+    
+    fn do_check_value(&self, value: &Value, depth: usize) -> Result<(), String> {
+        match *value {
+            Value::String(ref string) => {
+                self.check_string(string)
+            }
+            Value::Array(ref array) => {
+                try!(self.check_depth(depth));
+                if array.len() > self.max_array_len {
+                    return Err(format!("Array length {} exceeds limit of {}.", array.len(), self.max_array_len));
+                }
+                for elem in array {
+                    try!(self.do_check_value(elem, depth + 1));
+                }
+                Ok(())
+            }
+            Value::Object(ref object) => {
+                try!(self.check_depth(depth));
+                if object.len() > self.max_object_len {
+                    return Err(format!("Object member count {} exceeds limit of {}.", 
+                        object.len(), self.max_object_len));
+                }
+                for (key, elem) in object {
+                    try!(self.check_string(key));
+                    try!(self.do_check_value(elem, depth + 1));
+                }
+                Ok(())
+            }
+            _ => Ok(())
+        }
+    }
+    
+    fn check_depth(&self, depth: usize) -> Result<(), String> {
+        if depth > self.max_depth {
+            return Err(format!("Nesting depth exceeds limit of {}.", self.max_depth));
+        }
+        Ok(())
+    }
+    
+    fn check_string(&self, string: &str) -> Result<(), String> {
+        if string.len() > self.max_string_len {
+            return Err(format!("String length {} exceeds limit of {} bytes.", string.len(), self.max_string_len));
+        }
+        Ok(())
+    }
+}
+
+/// The default maximum size of a message: 32 MiB.
+pub const DEFAULT_MAX_MESSAGE_SIZE : usize = 32 * 1024 * 1024;
+
+impl Default for MessageLimits {
+    /// No limits, other than `DEFAULT_MAX_MESSAGE_SIZE` and `DECODE_DEPTH_LIMIT`.
+    fn default() -> Self {
+        MessageLimits { max_message_size : DEFAULT_MAX_MESSAGE_SIZE, .. MessageLimits::unlimited() }
+    }
+}
+
+
+/* -----------------  ----------------- */
+
+/// Decoded integers are mapped to `Value::U64` if non-negative, `Value::I64` otherwise,
+/// matching what serde_json itself produces.
+fn new_int_value(value: i64) -> Value {
+    if value >= 0 { Value::U64(value as u64) } else { Value::I64(value) }
+}
+
+/// Nesting depth limit of the binary decoders, to protect against stack overflow.
+/// This is the same limit serde_json applies when parsing.
+pub const DECODE_DEPTH_LIMIT : usize = 128;
+
+/// Cursor over the bytes being decoded.
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    remaining_depth: usize,
+}
+
+impl<'a> ByteReader<'a> {
+
+    fn new(bytes: &'a [u8]) -> ByteReader<'a> {
+        ByteReader { bytes : bytes, pos : 0, remaining_depth : DECODE_DEPTH_LIMIT }
+    }
+
+    fn enter_nested(&mut self) -> GResult<()> {
+        if self.remaining_depth == 0 {
+            return Err(format!("Recursion limit exceeded at byte {}.", self.pos).into());
+        }
+        self.remaining_depth -= 1;
+        Ok(())
+    }
+
+    fn exit_nested(&mut self) {
+        self.remaining_depth += 1;
+    }
+
+    fn read_bytes(&mut self, count: usize) -> GResult<&'a [u8]> {
+        if self.bytes.len() - self.pos < count {
+            return Err(format!("Unexpected end of input at byte {}.", self.bytes.len()).into());
+        }
+        let slice = &self.bytes[self.pos .. self.pos + count];
+        self.pos += count;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> GResult<u8> {
+        Ok(try!(self.read_bytes(1))[0])
+    }
+
+    fn peek_u8(&self) -> GResult<u8> {
+        match self.bytes.get(self.pos) {
+            Some(byte) => Ok(*byte),
+            None => Err(format!("Unexpected end of input at byte {}.", self.pos).into()),
+        }
+    }
+
+    fn read_uint(&mut self, size: usize) -> GResult<u64> {
+        let bytes = try!(self.read_bytes(size));
+        Ok(bytes.iter().fold(0u64, |acc, byte| (acc << 8) | (*byte as u64)))
+    }
+
+    fn read_string(&mut self, len: u64) -> GResult<String> {
+        let bytes = try!(self.read_bytes(len as usize));
+        Ok(try!(String::from_utf8(bytes.to_vec())))
+    }
+
+    fn check_finished(&self) -> GResult<()> {
+        if self.pos != self.bytes.len() {
+            return Err(format!("Trailing bytes after value, at byte {}.", self.pos).into());
+        }
+        Ok(())
+    }
+}
+
+fn write_uint(out: &mut Vec<u8>, value: u64, size: usize) {
+    for i in (0..size).rev() {
+        out.push((value >> (i * 8)) as u8);
+    }
+}
+
+fn write_f64(out: &mut Vec<u8>, value: f64) {
+    write_uint(out, value.to_bits(), 8);
+}
+
+fn read_f64(reader: &mut ByteReader) -> GResult<f64> {
+    Ok(f64::from_bits(try!(reader.read_uint(8))))
+}
+
+fn read_f32(reader: &mut ByteReader) -> GResult<f64> {
+    Ok(f32::from_bits(try!(reader.read_uint(4)) as u32) as f64)
+}
+
+
+/* -----------------  MessagePack  ----------------- */
+
+/// A codec for the MessagePack format (http://msgpack.org).
+/// Binary and extension types are not part of the JSON data model, and are rejected when decoding.
+pub struct MsgPackCodec;
+
+impl MessageCodec for MsgPackCodec {
+
+    fn encode_value(&self, value: &Value) -> GResult<Vec<u8>> {
+        let mut out = vec![];
+        msgpack_encode(&mut out, value);
+        Ok(out)
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> GResult<Value> {
+        let mut reader = ByteReader::new(bytes);
+        let value = try!(msgpack_decode(&mut reader));
+        try!(reader.check_finished());
+        Ok(value)
+    }
+}
+
+fn msgpack_write_header(out: &mut Vec<u8>, len: usize, fix_marker: u8, fix_max: usize, markers: [u8; 3]) {
+    if len <= fix_max {
+        out.push(fix_marker | len as u8);
+    } else if len <= 0xff && markers[0] != 0 {
+        out.push(markers[0]);
+        write_uint(out, len as u64, 1);
+    } else if len <= 0xffff {
+        out.push(markers[1]);
+        write_uint(out, len as u64, 2);
+    } else {
+        out.push(markers[2]);
+        write_uint(out, len as u64, 4);
+    }
+}
+
+fn msgpack_encode_u64(out: &mut Vec<u8>, num: u64) {
+    if num < 0x80 {
+        out.push(num as u8);
+    } else if num <= 0xff {
+        out.push(0xcc);
+        write_uint(out, num, 1);
+    } else if num <= 0xffff {
+        out.push(0xcd);
+        write_uint(out, num, 2);
+    } else if num <= 0xffff_ffff {
+        out.push(0xce);
+        write_uint(out, num, 4);
+    } else {
+        out.push(0xcf);
+        write_uint(out, num, 8);
+    }
+}
+
+fn msgpack_encode_i64(out: &mut Vec<u8>, num: i64) {
+    if num >= 0 {
+        msgpack_encode_u64(out, num as u64);
+    } else if num >= -32 {
+        out.push(num as u8);
+    } else if num >= -0x80 {
+        out.push(0xd0);
+        write_uint(out, num as u64, 1);
+    } else if num >= -0x8000 {
+        out.push(0xd1);
+        write_uint(out, num as u64, 2);
+    } else if num >= -0x8000_0000 {
+        out.push(0xd2);
+        write_uint(out, num as u64, 4);
+    } else {
+        out.push(0xd3);
+        write_uint(out, num as u64, 8);
+    }
+}
+
+fn msgpack_encode(out: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::U64(num) => msgpack_encode_u64(out, num),
+        Value::I64(num) => msgpack_encode_i64(out, num),
+        Value::F64(num) => {
+            out.push(0xcb);
+            write_f64(out, num);
+        }
+        Value::String(ref string) => {
+            msgpack_write_header(out, string.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+            out.extend_from_slice(string.as_bytes());
+        }
+        Value::Array(ref array) => {
+            msgpack_write_header(out, array.len(), 0x90, 15, [0, 0xdc, 0xdd]);
+            for elem in array {
+                msgpack_encode(out, elem);
+            }
+        }
+        Value::Object(ref object) => {
+            msgpack_write_header(out, object.len(), 0x80, 15, [0, 0xde, 0xdf]);
+            for (key, elem) in object {
+                msgpack_encode(out, &Value::String(key.clone()));
+                msgpack_encode(out, elem);
+            }
+        }
+    }
+}
+
+fn msgpack_decode(reader: &mut ByteReader) -> GResult<Value> {
+    let marker = try!(reader.read_u8());
+
+    let value = match marker {
+        0x00 ..= 0x7f => Value::U64(marker as u64),
+        0xe0 ..= 0xff => Value::I64((marker as i8) as i64),
+        0x80 ..= 0x8f => try!(msgpack_decode_map(reader, (marker & 0x0f) as u64)),
+        0x90 ..= 0x9f => try!(msgpack_decode_array(reader, (marker & 0x0f) as u64)),
+        0xa0 ..= 0xbf => Value::String(try!(reader.read_string((marker & 0x1f) as u64))),
+        0xc0 => Value::Null,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xca => Value::F64(try!(read_f32(reader))),
+        0xcb => Value::F64(try!(read_f64(reader))),
+        0xcc => Value::U64(try!(reader.read_uint(1))),
+        0xcd => Value::U64(try!(reader.read_uint(2))),
+        0xce => Value::U64(try!(reader.read_uint(4))),
+        0xcf => Value::U64(try!(reader.read_uint(8))),
+        0xd0 => new_int_value((try!(reader.read_uint(1)) as u8 as i8) as i64),
+        0xd1 => new_int_value((try!(reader.read_uint(2)) as u16 as i16) as i64),
+        0xd2 => new_int_value((try!(reader.read_uint(4)) as u32 as i32) as i64),
+        0xd3 => new_int_value(try!(reader.read_uint(8)) as i64),
+        0xd9 => { let len = try!(reader.read_uint(1)); Value::String(try!(reader.read_string(len))) }
+        0xda => { let len = try!(reader.read_uint(2)); Value::String(try!(reader.read_string(len))) }
+        0xdb => { let len = try!(reader.read_uint(4)); Value::String(try!(reader.read_string(len))) }
+        0xdc => { let len = try!(reader.read_uint(2)); try!(msgpack_decode_array(reader, len)) }
+        0xdd => { let len = try!(reader.read_uint(4)); try!(msgpack_decode_array(reader, len)) }
+        0xde => { let len = try!(reader.read_uint(2)); try!(msgpack_decode_map(reader, len)) }
+        0xdf => { let len = try!(reader.read_uint(4)); try!(msgpack_decode_map(reader, len)) }
+        _ => {
+            return Err(format!("Unsupported MessagePack marker `0x{:02x}`.", marker).into());
+        }
+    };
+    Ok(value)
+}
+
+fn msgpack_decode_array(reader: &mut ByteReader, len: u64) -> GResult<Value> {
+    try!(reader.enter_nested());
+    let mut array = vec![];
+    for _ in 0..len {
+        array.push(try!(msgpack_decode(reader)));
+    }
+    reader.exit_nested();
+    Ok(Value::Array(array))
+}
+
+fn msgpack_decode_map(reader: &mut ByteReader, len: u64) -> GResult<Value> {
+    try!(reader.enter_nested());
+    let mut object : JsonObject = BTreeMap::new();
+    for _ in 0..len {
+        let key = match try!(msgpack_decode(reader)) {
+            Value::String(key) => key,
+            key => return Err(format!("Map key `{}` is not a String.", key).into()),
+        };
+        let value = try!(msgpack_decode(reader));
+        object.insert(key, value);
+    }
+    reader.exit_nested();
+    Ok(Value::Object(object))
+}
+
+
+/* -----------------  CBOR  ----------------- */
+
+/// A codec for the CBOR format (RFC 7049).
+/// Byte strings, tags and simple values other than booleans and null are rejected when decoding.
+pub struct CborCodec;
+
+impl MessageCodec for CborCodec {
+
+    fn encode_value(&self, value: &Value) -> GResult<Vec<u8>> {
+        let mut out = vec![];
+        cbor_encode(&mut out, value);
+        Ok(out)
+    }
+
+    fn decode_value(&self, bytes: &[u8]) -> GResult<Value> {
+        let mut reader = ByteReader::new(bytes);
+        let value = try!(cbor_decode(&mut reader));
+        try!(reader.check_finished());
+        Ok(value)
+    }
+}
+
+const CBOR_INDEFINITE : u8 = 31;
+const CBOR_BREAK : u8 = 0xff;
+
+fn cbor_write_header(out: &mut Vec<u8>, major_type: u8, arg: u64) {
+    let major = major_type << 5;
+    if arg < 24 {
+        out.push(major | arg as u8);
+    } else if arg <= 0xff {
+        out.push(major | 24);
+        write_uint(out, arg, 1);
+    } else if arg <= 0xffff {
+        out.push(major | 25);
+        write_uint(out, arg, 2);
+    } else if arg <= 0xffff_ffff {
+        out.push(major | 26);
+        write_uint(out, arg, 4);
+    } else {
+        out.push(major | 27);
+        write_uint(out, arg, 8);
+    }
+}
+
+fn cbor_encode(out: &mut Vec<u8>, value: &Value) {
+    match *value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::U64(num) => cbor_write_header(out, 0, num),
+        Value::I64(num) => {
+            if num >= 0 {
+                cbor_write_header(out, 0, num as u64)
+            } else {
+                cbor_write_header(out, 1, (-1 - num) as u64)
+            }
+        }
+        Value::F64(num) => {
+            out.push(0xfb);
+            write_f64(out, num);
+        }
+        Value::String(ref string) => {
+            cbor_write_header(out, 3, string.len() as u64);
+            out.extend_from_slice(string.as_bytes());
+        }
+        Value::Array(ref array) => {
+            cbor_write_header(out, 4, array.len() as u64);
+            for elem in array {
+                cbor_encode(out, elem);
+            }
+        }
+        Value::Object(ref object) => {
+            cbor_write_header(out, 5, object.len() as u64);
+            for (key, elem) in object {
+                cbor_encode(out, &Value::String(key.clone()));
+                cbor_encode(out, elem);
+            }
+        }
+    }
+}
+
+/// Read the argument of a data item header. Returns None for indefinite length.
+fn cbor_read_arg(reader: &mut ByteReader, info: u8) -> GResult<Option<u64>> {
+    match info {
+        0 ..= 23 => Ok(Some(info as u64)),
+        24 => Ok(Some(try!(reader.read_uint(1)))),
+        25 => Ok(Some(try!(reader.read_uint(2)))),
+        26 => Ok(Some(try!(reader.read_uint(4)))),
+        27 => Ok(Some(try!(reader.read_uint(8)))),
+        CBOR_INDEFINITE => Ok(None),
+        _ => Err(format!("Invalid CBOR additional information `{}`.", info).into()),
+    }
+}
+
+/// Returns true, and consumes the byte, if the next byte is a break marker.
+fn cbor_at_break(reader: &mut ByteReader) -> GResult<bool> {
+    if try!(reader.peek_u8()) == CBOR_BREAK {
+        try!(reader.read_u8());
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+fn cbor_decode(reader: &mut ByteReader) -> GResult<Value> {
+    let initial = try!(reader.read_u8());
+    let major_type = initial >> 5;
+    let info = initial & 0x1f;
+
+    if major_type == 7 {
+        return cbor_decode_simple(reader, info);
+    }
+
+    let arg = try!(cbor_read_arg(reader, info));
+
+    let value = match (major_type, arg) {
+        (0, Some(num)) => Value::U64(num),
+        (1, Some(num)) => {
+            if num > (i64::max_value() as u64) {
+                return Err(format!("Negative integer -1-{} is out of range.", num).into());
+            }
+            Value::I64(-1 - (num as i64))
+        }
+        (3, Some(len)) => Value::String(try!(reader.read_string(len))),
+        (3, None) => {
+            try!(reader.enter_nested());
+            let mut string = String::new();
+            while !try!(cbor_at_break(reader)) {
+                string.push_str(&try!(cbor_read_text_chunk(reader)));
+            }
+            reader.exit_nested();
+            Value::String(string)
+        }
+        (4, len) => {
+            try!(reader.enter_nested());
+            let mut array = vec![];
+            let mut count = 0;
+            loop {
+                match len {
+                    Some(len) => if count == len { break },
+                    None => if try!(cbor_at_break(reader)) { break },
+                }
+                array.push(try!(cbor_decode(reader)));
+                count += 1;
+            }
+            reader.exit_nested();
+            Value::Array(array)
+        }
+        (5, len) => {
+            try!(reader.enter_nested());
+            let mut object : JsonObject = BTreeMap::new();
+            let mut count = 0;
+            loop {
+                match len {
+                    Some(len) => if count == len { break },
+                    None => if try!(cbor_at_break(reader)) { break },
+                }
+                let key = match try!(cbor_decode(reader)) {
+                    Value::String(key) => key,
+                    key => return Err(format!("Map key `{}` is not a String.", key).into()),
+                };
+                let value = try!(cbor_decode(reader));
+                object.insert(key, value);
+                count += 1;
+            }
+            reader.exit_nested();
+            Value::Object(object)
+        }
+        _ => {
+            return Err(format!("Unsupported CBOR major type `{}`.", major_type).into());
+        }
+    };
+    Ok(value)
+}
+
+/// Read a chunk of an indefinite-length text string, which must be a definite-length text string
+/// (RFC 7049, section 2.2.2).
+fn cbor_read_text_chunk(reader: &mut ByteReader) -> GResult<String> {
+    let initial = try!(reader.read_u8());
+    if initial >> 5 == 3 {
+        if let Some(len) = try!(cbor_read_arg(reader, initial & 0x1f)) {
+            return reader.read_string(len);
+        }
+    }
+    Err("Indefinite-length string chunk is not a definite-length text string.".into())
+}
+
+fn cbor_decode_simple(reader: &mut ByteReader, info: u8) -> GResult<Value> {
+    let value = match info {
+        20 => Value::Bool(false),
+        21 => Value::Bool(true),
+        22 => Value::Null,
+        25 => Value::F64(cbor_f16_to_f64(try!(reader.read_uint(2)) as u16)),
+        26 => Value::F64(try!(read_f32(reader))),
+        27 => Value::F64(try!(read_f64(reader))),
+        _ => return Err(format!("Unsupported CBOR simple value `{}`.", info).into()),
+    };
+    Ok(value)
+}
+
+fn cbor_f16_to_f64(half: u16) -> f64 {
+    let exponent = ((half >> 10) & 0x1f) as i32;
+    let mantissa = (half & 0x3ff) as f64;
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 => if mantissa == 0.0 { ::std::f64::INFINITY } else { ::std::f64::NAN },
+        _ => (mantissa + 1024.0) * 2f64.powi(exponent - 25),
+    };
+    if half & 0x8000 != 0 { -magnitude } else { magnitude }
+}
+
+
+/* -----------------  ----------------- */
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    use util::tests::*;
+    use serde_json::Value;
+    use serde_json::builder::ObjectBuilder;
+
+    use json_util::*;
+    use jsonrpc_common::*;
+    use jsonrpc_message::Message;
+    use jsonrpc_request::*;
+    use jsonrpc_response::*;
+    use jsonrpc_response::response_tests::sample_json_obj;
+
+    fn test_codec_message(codec: &MessageCodec, message: Message) {
+        let bytes = codec.encode_message(&message, &MessageFormat::default()).unwrap();
+        assert_equal(codec.decode_message(&bytes).unwrap(), message);
+    }
+
+    fn test_codec_value(codec: &MessageCodec, value: Value) {
+        let bytes = codec.encode_value(&value).unwrap();
+        assert_equal(codec.decode_value(&bytes).unwrap(), value);
+    }
+
+    fn test_codec(codec: &MessageCodec) {
+        test_codec_value(codec, Value::Null);
+        test_codec_value(codec, Value::Bool(true));
+        test_codec_value(codec, Value::U64(0));
+        test_codec_value(codec, Value::U64(200));
+        test_codec_value(codec, Value::U64(70000));
+        test_codec_value(codec, Value::U64(u64::max_value()));
+        test_codec_value(codec, Value::I64(-1));
+        test_codec_value(codec, Value::I64(-200));
+        test_codec_value(codec, Value::I64(i64::min_value()));
+        test_codec_value(codec, Value::F64(1.5));
+        test_codec_value(codec, Value::String("".into()));
+        test_codec_value(codec, Value::String("x".repeat(300)));
+        test_codec_value(codec, Value::Array((0..20).map(Value::U64).collect()));
+        test_codec_value(codec, sample_json_obj(123));
+
+        let sample_params = unwrap_object(sample_json_obj(123));
+        test_codec_message(codec, Request::new(1, "myMethod".into(), sample_params).into());
+        test_codec_message(codec, Request {
+            id : Some(Id::String("abc".into())), method : "myMethod".into(), params : RequestParams::None,
+            extensions : new_object()
+        }.into());
+        test_codec_message(codec, Request {
+            id : None, method : "myMethod".into(), params : RequestParams::Array(vec![Value::I64(-4)]),
+            extensions : new_object()
+        }.into());
+
+        test_codec_message(codec, Response::new_result(Id::Null, sample_json_obj(100)).into());
+        test_codec_message(codec, Response::new_result(Id::Number(123), Value::Null).into());
+        test_codec_message(codec, Response::new_result(Id::Integer(-123), Value::Null).into());
+        test_codec_message(codec, Response::new_result(Id::Float(0.5), Value::Null).into());
+        test_codec_message(codec, Response::new_error(Id::String("321".into()), RequestError {
+            code : -32000, message : "msg".into(), data : Some(sample_json_obj(300))
+        }).into());
+        test_codec_message(codec, Response::new_error(Id::Number(1), error_JSON_RPC_MethodNotFound()).into());
+    }
+
+    #[test]
+    fn test_JsonCodec() {
+        test_codec(&JsonCodec);
+
+        let response = Response::new_result(Id::Number(1), Value::Null);
+        assert_equal(JsonCodec.encode_message(&response.into(), &MessageFormat::default()).unwrap(),
+            br#"{"jsonrpc":"2.0","id":1,"result":null}"#.to_vec());
+    }
+
+    #[test]
+    fn test_MsgPackCodec() {
+        test_codec(&MsgPackCodec);
+
+        let value = ObjectBuilder::new().insert("id", -3).insert("a", vec![true]).build();
+        assert_equal(MsgPackCodec.encode_value(&value).unwrap(),
+            vec![0x82, 0xa1, b'a', 0x91, 0xc3, 0xa2, b'i', b'd', 0xfd]);
+
+        check_err_contains(MsgPackCodec.decode_value(&[0xc4, 0x00]).unwrap_err(), "Unsupported MessagePack");
+        check_err_contains(MsgPackCodec.decode_value(&[0x92, 0xc0]).unwrap_err(), "Unexpected end of input");
+        check_err_contains(MsgPackCodec.decode_value(&[0xc0, 0xc0]).unwrap_err(), "Trailing bytes");
+        check_err_contains(MsgPackCodec.decode_value(&[0x81, 0x01, 0xc0]).unwrap_err(), "is not a String");
+    }
+
+    #[test]
+    fn test_DecodeDepthLimit() {
+        let mut deep = Value::Null;
+        for _ in 0..DECODE_DEPTH_LIMIT {
+            deep = Value::Array(vec![deep]);
+        }
+        test_codec_value(&MsgPackCodec, deep.clone());
+        test_codec_value(&CborCodec, deep.clone());
+        
+        let too_deep = Value::Array(vec![deep]);
+        let bytes = MsgPackCodec.encode_value(&too_deep).unwrap();
+        check_err_contains(MsgPackCodec.decode_value(&bytes).unwrap_err(), "Recursion limit exceeded");
+        let bytes = CborCodec.encode_value(&too_deep).unwrap();
+        check_err_contains(CborCodec.decode_value(&bytes).unwrap_err(), "Recursion limit exceeded");
+    }
+    
+    #[test]
+    fn test_MessageLimits() {
+        let limits = MessageLimits { 
+            max_message_size : 10, max_depth : 2, max_array_len : 2, max_object_len : 1, max_string_len : 3,
+        };
+        
+        assert!(limits.check_message_size(10).is_ok());
+        check_err_contains(limits.check_message_size(11).unwrap_err(), "Message size 11 exceeds limit");
+        
+        assert!(limits.check_value(&ObjectBuilder::new().insert("abc", vec!["abc", "x"]).build()).is_ok());
+        check_err_contains(limits.check_value(&Value::Array(vec![Value::Array(vec![Value::Array(vec![])])]))
+            .unwrap_err(), "Nesting depth exceeds limit of 2");
+        check_err_contains(limits.check_value(&Value::Array(vec![Value::Null; 3])).unwrap_err(), 
+            "Array length 3 exceeds limit of 2");
+        check_err_contains(limits.check_value(&ObjectBuilder::new().insert("a", 1).insert("b", 2).build())
+            .unwrap_err(), "Object member count 2 exceeds limit of 1");
+        check_err_contains(limits.check_value(&ObjectBuilder::new().insert("abcd", 1).build()).unwrap_err(), 
+            "String length 4 exceeds limit");
+        
+        assert!(MessageLimits::default().check_value(&sample_json_obj(1)).is_ok());
+    }
+    
+    #[test]
+    fn test_CborCodec() {
+        test_codec(&CborCodec);
+
+        let value = ObjectBuilder::new().insert("id", -3).insert("a", vec![true]).build();
+        assert_equal(CborCodec.encode_value(&value).unwrap(),
+            vec![0xa2, 0x61, b'a', 0x81, 0xf5, 0x62, b'i', b'd', 0x22]);
+
+        // Indefinite-length array and string, and half-precision float
+        assert_equal(CborCodec.decode_value(&[0x9f, 0x7f, 0x61, b'a', 0x61, b'b', 0xff, 0xf9, 0x3e, 0x00, 0xff]).unwrap(),
+            Value::Array(vec![Value::String("ab".into()), Value::F64(1.5)]));
+
+        check_err_contains(CborCodec.decode_value(&[0x7f, 0x7f, 0x61, b'a', 0xff, 0xff]).unwrap_err(), 
+            "chunk is not a definite-length text string");
+        check_err_contains(CborCodec.decode_value(&[0x7f, 0x01, 0xff]).unwrap_err(), 
+            "chunk is not a definite-length text string");
+        check_err_contains(CborCodec.decode_value(&vec![0x7f; 200_000]).unwrap_err(), 
+            "chunk is not a definite-length text string");
+        check_err_contains(CborCodec.decode_value(&[0x41, 0x00]).unwrap_err(), "Unsupported CBOR major type");
+        check_err_contains(CborCodec.decode_value(&[0x3b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff])
+            .unwrap_err(), "out of range");
+    }
+
+}
@@ -7,10 +7,19 @@
 
 use std;
 
+use std::error;
+use std::fmt;
+use std::io;
 use std::thread;
-use std::sync::mpsc;
-use std::sync::mpsc::Receiver;
-use std::sync::mpsc::SendError;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+use futures::Async;
+use futures::Future;
+use futures::Poll;
+use futures::task;
 
 #[allow(unused_imports)]
 use util::core::*;
@@ -38,27 +47,465 @@ impl<FN : FnOnce(AgentInnerRunner)> AgentRunnable for FN {
 
 pub type OutputAgentTask = Box<Fn(&mut MessageWriter) + Send>;
 
+/// The priority of an OutputAgent task. Queued tasks of higher priority are executed first, 
+/// tasks of the same priority are executed in the order they were submitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TaskPriority {
+    /// Responses and control messages, such as cancel or ping.
+    High,
+    /// The default priority.
+    Normal,
+    /// Bulk traffic that may be delayed by any other message, such as progress notifications.
+    Low,
+}
+
+/// The priorities of the task queue lanes, highest first.
+const PRIORITY_LANES : [TaskPriority; 3] = [TaskPriority::High, TaskPriority::Normal, TaskPriority::Low];
+
+impl TaskPriority {
+    fn lane(self) -> usize {
+        match self {
+            TaskPriority::High => 0,
+            TaskPriority::Normal => 1,
+            TaskPriority::Low => 2,
+        }
+    }
+}
+
 pub enum OutputAgentMessage {
+    /// Stop the agent, once all queued tasks are executed.
     Shutdown,
-    Task(OutputAgentTask),
+    Task(OutputAgentTask, TaskPriority),
 }
 
 const ERR_SEND_TASK_FAILED : &'static str =
     "Failed to send task, Agent receive channel is closed.";
 
+/// The capacity of the task queue of an OutputAgent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskQueueBound {
+    /// Tasks are queued without limit. 
+    Unbounded,
+    /// At most `capacity` tasks are queued, across all priorities. When the queue is full, 
+    /// submitting a task blocks or fails, according to `when_full`. A capacity of 0 is treated as 1.
+    Bounded { capacity : usize, when_full : QueueFullPolicy },
+}
+
+/// What submitting a task to a full bounded task queue does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueFullPolicy {
+    /// Block until the agent takes a task from the queue.
+    Block,
+    /// Fail with `SubmitTaskError::QueueFull`.
+    Fail,
+}
+
+/// Configuration of an OutputAgent started with a message writer provider.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputAgentOptions {
+    pub queue_bound : TaskQueueBound,
+    /// If true, the agent executes all queued tasks before calling `MessageWriter::flush` once.
+    /// This saves a flush per message under load, but only helps with a writer that doesn't flush 
+    /// each message itself, such as `BufferedWriteLineMessageWriter`.
+    /// 
+    /// Default is false: flushing is left to the writer, which typically flushes each message.
+    pub flush_when_idle : bool,
+}
+
+impl Default for OutputAgentOptions {
+    fn default() -> OutputAgentOptions {
+        OutputAgentOptions { queue_bound : TaskQueueBound::Unbounded, flush_when_idle : false }
+    }
+}
+
+/// The error of submitting a task to an OutputAgent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubmitTaskError {
+    /// Shutdown of the agent was requested.
+    Shutdown,
+    /// The task queue is bounded and full, and the policy is to fail.
+    QueueFull,
+    /// The agent is no longer receiving tasks, even though shutdown wasn't requested. 
+    /// Typically this means the agent thread died.
+    Disconnected,
+    /// The agent is in the failed state.
+    Failed(OutputFailure),
+}
+
+impl fmt::Display for SubmitTaskError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SubmitTaskError::Failed(ref failure) => write!(fmt, "{}", failure),
+            SubmitTaskError::Shutdown => fmt.write_str("Output agent is shut down."),
+            SubmitTaskError::QueueFull => fmt.write_str("Output agent task queue is full."),
+            SubmitTaskError::Disconnected => fmt.write_str(ERR_SEND_TASK_FAILED),
+        }
+    }
+}
+
+impl error::Error for SubmitTaskError {
+}
+
+/// The error that put an OutputAgent in the failed state, typically an error writing to the output. 
+#[derive(Debug, Clone)]
+pub struct OutputFailure {
+    pub message : String,
+    error : Arc<error::Error + Send + Sync>,
+}
+
+impl OutputFailure {
+    
+    /// Create the failure caused by given error. 
+    /// An `io::Error`, the typical output error, is kept as is. Other errors can't be sent across threads,
+    /// so they are replaced with an error of the same message.
+    pub fn new(error: GError) -> OutputFailure {
+        let message = format!("{}", error);
+        let error : Box<error::Error + Send + Sync> = match error.downcast::<io::Error>() {
+            Ok(io_error) => io_error,
+            Err(_) => message.clone().into(),
+        };
+        OutputFailure { message : message, error : Arc::from(error) }
+    }
+    
+    /// The error that caused the failure.
+    pub fn error(&self) -> &Arc<error::Error + Send + Sync> {
+        &self.error
+    }
+    
+}
+
+impl PartialEq for OutputFailure {
+    fn eq(&self, other: &OutputFailure) -> bool {
+        self.message == other.message
+    }
+}
+
+impl fmt::Display for OutputFailure {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Output agent failed: {}", self.message)
+    }
+}
+
+impl error::Error for OutputFailure {
+    fn source(&self) -> Option<&(error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// A listener of the failure of an OutputAgent. See `FailureHandle::on_failure`.
+pub type FailureListener = Box<Fn(&OutputFailure) + Send>;
+
+struct FailureState {
+    failure : Option<OutputFailure>,
+    listeners : Vec<FailureListener>,
+}
+
+/// A handle to the failed state of an OutputAgent. 
+/// Tasks, and the agent itself, use it to report a failure, after which the agent no longer accepts tasks.
+#[derive(Clone)]
+pub struct FailureHandle(Arc<Mutex<FailureState>>);
+
+impl FailureHandle {
+    
+    fn new() -> FailureHandle {
+        FailureHandle(Arc::new(Mutex::new(FailureState { failure : None, listeners : vec![] })))
+    }
+    
+    /// Put the agent in the failed state, caused by given error, and notify the failure listeners.
+    /// Only the first failure is kept.
+    pub fn fail(&self, error: GError) {
+        let (failure, listeners) = {
+            let mut state = self.0.lock().unwrap();
+            if state.failure.is_some() {
+                return;
+            }
+            let failure = OutputFailure::new(error);
+            state.failure = Some(failure.clone());
+            (failure, std::mem::replace(&mut state.listeners, vec![]))
+        };
+        for listener in listeners {
+            listener(&failure);
+        }
+    }
+    
+    /// Register given listener, to be invoked once the agent fails. 
+    /// If the agent already failed, it is invoked immediately.
+    pub fn on_failure(&self, listener: FailureListener) {
+        let failure = {
+            let mut state = self.0.lock().unwrap();
+            match state.failure {
+                Some(ref failure) => failure.clone(),
+                None => {
+                    state.listeners.push(listener);
+                    return;
+                }
+            }
+        };
+        listener(&failure);
+    }
+    
+    pub fn failure(&self) -> Option<OutputFailure> {
+        self.0.lock().unwrap().failure.clone()
+    }
+    
+    pub fn is_failed(&self) -> bool {
+        self.0.lock().unwrap().failure.is_some()
+    }
+}
+
+impl fmt::Debug for FailureHandle {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "FailureHandle({:?})", self.failure())
+    }
+}
+
+/* -----------------  Task queue  ----------------- */
+
+struct TaskQueueState {
+    /// One FIFO queue per priority, highest first
+    lanes : Vec<VecDeque<OutputAgentTask>>,
+    len : usize,
+    shutdown : bool,
+    sender_alive : bool,
+    receiver_alive : bool,
+    /// The futures task waiting for a message, if the receiver is polled
+    parked_task : Option<task::Task>,
+}
+
+/// The task queue of an OutputAgent, with a lane for each `TaskPriority`.
+struct TaskQueue {
+    state : Mutex<TaskQueueState>,
+    bound : TaskQueueBound,
+    /// Signaled when a message is queued, or the sender is dropped
+    message_queued : Condvar,
+    /// Signaled when a task is taken, or the receiver is dropped
+    task_taken : Condvar,
+}
+
+impl TaskQueue {
+    
+    fn new(bound: TaskQueueBound) -> (TaskQueueSender, TaskQueueReceiver) {
+        let state = TaskQueueState { 
+            lanes : PRIORITY_LANES.iter().map(|_| VecDeque::new()).collect(),
+            len : 0,
+            shutdown : false,
+            sender_alive : true,
+            receiver_alive : true,
+            parked_task : None,
+        };
+        let queue = Arc::new(TaskQueue { 
+            state : Mutex::new(state), 
+            bound : bound, 
+            message_queued : Condvar::new(), 
+            task_taken : Condvar::new(),
+        });
+        (TaskQueueSender(queue.clone()), TaskQueueReceiver(queue))
+    }
+    
+    fn is_full(&self, state: &TaskQueueState) -> bool {
+        match self.bound {
+            TaskQueueBound::Unbounded => false,
+            TaskQueueBound::Bounded { capacity, .. } => state.len >= std::cmp::max(capacity, 1),
+        }
+    }
+    
+}
+
+/// The sending end of the task queue.
+struct TaskQueueSender(Arc<TaskQueue>);
+
+impl TaskQueue {
+    
+    /// Queue given message. A task blocks or fails if the queue is full, according to the queue's policy,
+    /// and fails once shutdown was requested, releasing blocked senders. `Shutdown` never blocks.
+    fn send(&self, message: OutputAgentMessage) -> Result<(), SubmitTaskError> {
+        let queue = self;
+        let mut state = queue.state.lock().unwrap();
+        
+        match message {
+            OutputAgentMessage::Shutdown => {
+                if !state.receiver_alive {
+                    return Err(SubmitTaskError::Disconnected);
+                }
+                state.shutdown = true;
+                // Release senders blocked on a full queue
+                queue.task_taken.notify_all();
+            }
+            OutputAgentMessage::Task(task, priority) => {
+                loop {
+                    // Shutdown first: the receiver goes away once it has processed a shutdown
+                    if state.shutdown {
+                        return Err(SubmitTaskError::Shutdown);
+                    }
+                    if !state.receiver_alive {
+                        return Err(SubmitTaskError::Disconnected);
+                    }
+                    if !queue.is_full(&state) {
+                        break;
+                    }
+                    match queue.bound {
+                        TaskQueueBound::Bounded { when_full : QueueFullPolicy::Block, .. } => {
+                            state = queue.task_taken.wait(state).unwrap();
+                        }
+                        _ => return Err(SubmitTaskError::QueueFull),
+                    }
+                }
+                state.lanes[priority.lane()].push_back(task);
+                state.len += 1;
+            }
+        }
+        queue.notify_message_queued(&mut state);
+        Ok(())
+    }
+    
+    fn notify_message_queued(&self, state: &mut TaskQueueState) {
+        self.message_queued.notify_all();
+        if let Some(parked_task) = state.parked_task.take() {
+            parked_task.notify();
+        }
+    }
+}
+
+impl TaskQueueSender {
+    fn send(&self, message: OutputAgentMessage) -> Result<(), SubmitTaskError> {
+        self.0.send(message)
+    }
+}
+
+impl Drop for TaskQueueSender {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.sender_alive = false;
+        self.0.notify_message_queued(&mut state);
+    }
+}
+
+/// The receiving end of the task queue of an OutputAgent.
+pub struct TaskQueueReceiver(Arc<TaskQueue>);
+
+impl TaskQueueReceiver {
+    
+    /// Take the next message, blocking until one is available. 
+    /// Tasks are taken highest priority first, `Shutdown` only once no tasks are queued.
+    /// Returns None if the sending end was dropped without requesting shutdown.
+    pub fn receive(&self) -> Option<OutputAgentMessage> {
+        let queue = &self.0;
+        let mut state = queue.state.lock().unwrap();
+        loop {
+            if let Some(message) = self.take_message(&mut state) {
+                return Some(message);
+            }
+            if !state.sender_alive {
+                return None;
+            }
+            state = queue.message_queued.wait(state).unwrap();
+        }
+    }
+    
+    /// Take the next message, if one is available.
+    pub fn try_receive(&self) -> Option<OutputAgentMessage> {
+        let mut state = self.0.state.lock().unwrap();
+        self.take_message(&mut state)
+    }
+    
+    /// Take the next message if one is available, like `receive`, but without blocking.
+    /// Otherwise the current futures task is notified once a message is available. 
+    /// Must be called from within a futures task.
+    pub fn poll_receive(&self) -> Async<Option<OutputAgentMessage>> {
+        let mut state = self.0.state.lock().unwrap();
+        if let Some(message) = self.take_message(&mut state) {
+            return Async::Ready(Some(message));
+        }
+        if !state.sender_alive {
+            return Async::Ready(None);
+        }
+        state.parked_task = Some(task::current());
+        Async::NotReady
+    }
+    
+    fn take_message(&self, state: &mut TaskQueueState) -> Option<OutputAgentMessage> {
+        for (lane, priority) in state.lanes.iter_mut().zip(PRIORITY_LANES.iter()) {
+            if let Some(task) = lane.pop_front() {
+                state.len -= 1;
+                self.0.task_taken.notify_one();
+                return Some(OutputAgentMessage::Task(task, *priority));
+            }
+        }
+        
+        if state.shutdown {
+            Some(OutputAgentMessage::Shutdown)
+        } else {
+            None
+        }
+    }
+    
+}
+
+impl Drop for TaskQueueReceiver {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().receiver_alive = false;
+        self.0.task_taken.notify_all();
+    }
+}
+
+/// A handle to submit tasks to an OutputAgent, obtained with `OutputAgent::task_submitter`.
+/// 
+/// Unlike the OutputAgent, it can be cloned and shared between threads. 
+/// So the agent can be kept behind a lock, and submitting with `QueueFullPolicy::Block` 
+/// can wait for the queue without holding that lock.
+/// A submitter doesn't keep the agent alive: once the agent is shut down or dropped, submitting fails.
+#[derive(Clone)]
+pub struct TaskSubmitter {
+    task_queue : Arc<TaskQueue>,
+    failure : FailureHandle,
+}
+
+impl TaskSubmitter {
+    
+    /// Submit given task. See `OutputAgent::try_submit_task_with_priority`.
+    pub fn try_submit_task_with_priority(&self, task : OutputAgentTask, priority: TaskPriority) 
+        -> Result<(), SubmitTaskError> 
+    {
+        if let Some(failure) = self.failure.failure() {
+            return Err(SubmitTaskError::Failed(failure));
+        }
+        self.task_queue.send(OutputAgentMessage::Task(task, priority))
+    }
+    
+    /// The handle tasks use to report a failure.
+    pub fn failure_handle(&self) -> FailureHandle {
+        self.failure.clone()
+    }
+    
+}
+
+/* -----------------  ----------------- */
+
 /**
 
 Actor-like, dedicated worker thread that handles writing to an output stream.
 Accepts tasks as messages, which are executed by the agent.
+Alternatively, an agent started with `start_polled` has no thread: its tasks are executed 
+by an `OutputDriver` future, polled for example by an event loop.
 
 Note that the OutputAgent type is not meant to be Sync, it is meant to be synchronized externally,
 or more typically, used by one controlling thread only. 
 
+Dropping the OutputAgent requests shutdown, but doesn't wait for the agent thread to finish 
+executing queued tasks. Use `shutdown_and_join` for a deterministic teardown.
+
+Tasks are submitted with a `TaskPriority`: queued tasks of higher priority are executed first,
+so that, for example, responses are not delayed by a flood of other messages.
+
+By default the task queue is unbounded. A bounded queue (see `TaskQueueBound`) limits the memory used 
+when tasks are submitted faster than the agent can execute them.
+
  */
 pub struct OutputAgent {
     is_shutdown : bool,
     output_thread : Option<thread::JoinHandle<()>>,
-    task_queue : mpsc::Sender<OutputAgentMessage>,
+    task_queue : TaskQueueSender,
+    failure : FailureHandle,
 }
 
 impl OutputAgent {
@@ -69,37 +516,135 @@ impl OutputAgent {
         OUT : MessageWriter + 'static, 
         OUT_P : FnOnce() -> OUT + Send + 'static 
     {
-        Self::start(move |inner_runner: AgentInnerRunner| {
+        Self::start_with_provider_and_options(msg_writer_provider, OutputAgentOptions::default())
+    }
+    
+    pub fn start_with_provider_and_bound<OUT, OUT_P>(msg_writer_provider: OUT_P, bound: TaskQueueBound) 
+        -> OutputAgent
+    where 
+        OUT : MessageWriter + 'static, 
+        OUT_P : FnOnce() -> OUT + Send + 'static 
+    {
+        let options = OutputAgentOptions { queue_bound : bound, .. OutputAgentOptions::default() };
+        Self::start_with_provider_and_options(msg_writer_provider, options)
+    }
+    
+    pub fn start_with_provider_and_options<OUT, OUT_P>(msg_writer_provider: OUT_P, options: OutputAgentOptions) 
+        -> OutputAgent
+    where 
+        OUT : MessageWriter + 'static, 
+        OUT_P : FnOnce() -> OUT + Send + 'static 
+    {
+        Self::start_with_bound(move |inner_runner: AgentInnerRunner| {
             let mut msg_writer: OUT = msg_writer_provider();
             
-            inner_runner.enter_agent_loop(&mut move |task: OutputAgentTask| {
-                task(&mut msg_writer); 
-            });
-        })
+            if options.flush_when_idle {
+                inner_runner.enter_flushing_agent_loop(&mut msg_writer);
+            } else {
+                inner_runner.enter_agent_loop(&mut move |task: OutputAgentTask| {
+                    task(&mut msg_writer); 
+                });
+            }
+        }, options.queue_bound)
     }
     
     
+    /// Start an agent that doesn't spawn a thread. Tasks are executed by the returned `OutputDriver`,
+    /// a future that must be polled, typically by spawning it on an event loop.
+    /// 
+    /// Such an agent has no thread to join: `shutdown_and_join` only requests shutdown, 
+    /// and the driver completes once the queued tasks are executed. 
+    /// Note that `QueueFullPolicy::Block` blocks the submitting thread, 
+    /// so it can't be used if that thread also polls the driver.
+    pub fn start_polled<OUT>(msg_writer: OUT, options: OutputAgentOptions) 
+        -> (OutputAgent, OutputDriver<OUT>)
+    where 
+        OUT : MessageWriter,
+    {
+        let (tx, rx) = TaskQueue::new(options.queue_bound);
+        let failure = FailureHandle::new();
+        
+        let driver = OutputDriver { 
+            rx : rx, 
+            failure : failure.clone(), 
+            msg_writer : msg_writer, 
+            flush_when_idle : options.flush_when_idle,
+        };
+        let agent = OutputAgent { is_shutdown : false, task_queue : tx, output_thread : None, 
+            failure : failure };
+        (agent, driver)
+    }
+    
     pub fn start<AGENT_RUNNER>(agent_runner: AGENT_RUNNER) 
         -> OutputAgent
     where 
         AGENT_RUNNER : AgentRunnable,
         AGENT_RUNNER : Send + 'static,
     {
-        let (tx, rx) = mpsc::channel::<OutputAgentMessage>();
+        Self::start_with_bound(agent_runner, TaskQueueBound::Unbounded)
+    }
+    
+    pub fn start_with_bound<AGENT_RUNNER>(agent_runner: AGENT_RUNNER, bound: TaskQueueBound) 
+        -> OutputAgent
+    where 
+        AGENT_RUNNER : AgentRunnable,
+        AGENT_RUNNER : Send + 'static,
+    {
+        let (tx, rx) = TaskQueue::new(bound);
+        let failure = FailureHandle::new();
+        let runner_failure = failure.clone();
         
         let output_thread = thread::spawn(move || {
-            agent_runner.run_agent(AgentInnerRunner{ rx : rx });
+            agent_runner.run_agent(AgentInnerRunner{ rx : rx, failure : runner_failure });
         });
         
-        OutputAgent { is_shutdown : false, task_queue : tx,  output_thread : Some(output_thread) }     
+        OutputAgent { is_shutdown : false, task_queue : tx,  output_thread : Some(output_thread), 
+            failure : failure }     
     }
     
+    /// Whether shutdown was requested. Note that failing doesn't request shutdown.
     pub fn is_shutdown(&self) -> bool {
         self.is_shutdown
     }
     
-    pub fn try_submit_task(& self, task : OutputAgentTask) -> Result<(), SendError<OutputAgentMessage>> {
-        self.task_queue.send(OutputAgentMessage::Task(task))
+    /// The handle tasks use to report a failure.
+    pub fn failure_handle(&self) -> FailureHandle {
+        self.failure.clone()
+    }
+    
+    /// Obtain a handle to submit tasks to this agent, without access to the agent itself.
+    pub fn task_submitter(&self) -> TaskSubmitter {
+        TaskSubmitter { task_queue : self.task_queue.0.clone(), failure : self.failure.clone() }
+    }
+    
+    /// The failure that put this agent in the failed state, if any.
+    pub fn failure(&self) -> Option<OutputFailure> {
+        self.failure.failure()
+    }
+    
+    pub fn is_failed(&self) -> bool {
+        self.failure.is_failed()
+    }
+    
+    /// Submit given task, with `TaskPriority::Normal`. See `try_submit_task_with_priority`.
+    pub fn try_submit_task(& self, task : OutputAgentTask) -> Result<(), SubmitTaskError> {
+        self.try_submit_task_with_priority(task, TaskPriority::Normal)
+    }
+    
+    /// Submit given task. If the task queue is bounded and full, 
+    /// this blocks or fails with `SubmitTaskError::QueueFull`, according to the queue's policy.
+    /// Fails with `SubmitTaskError::Failed` if the agent is in the failed state,
+    /// and with `SubmitTaskError::Shutdown` if shutdown was requested.
+    pub fn try_submit_task_with_priority(& self, task : OutputAgentTask, priority: TaskPriority) 
+        -> Result<(), SubmitTaskError> 
+    {
+        if self.is_shutdown {
+            return Err(SubmitTaskError::Shutdown);
+        }
+        if let Some(failure) = self.failure() {
+            return Err(SubmitTaskError::Failed(failure));
+        }
+        self.task_queue.send(OutputAgentMessage::Task(task, priority))
     }
     
     pub fn submit_task(& self, task : OutputAgentTask) {
@@ -139,16 +684,15 @@ impl OutputAgent {
 impl Drop for OutputAgent {
     
     fn drop(&mut self) {
-        if !thread::panicking() {
-            // User must have taken care of shutdown itself, otherwise thread is leaked.
-            assert!(self.is_shutdown());
-        }
+        // The agent thread is not joined: it stops by itself once queued tasks are executed.
+        self.request_shutdown();
     }
     
 }
 
 pub struct AgentInnerRunner {
-    rx: Receiver<OutputAgentMessage>,
+    rx: TaskQueueReceiver,
+    failure: FailureHandle,
 }
 impl AgentInnerRunner {
     
@@ -161,23 +705,58 @@ impl AgentInnerRunner {
         Self::run_agent_loop(&mut rx, task_runner);
     }
     
-    pub fn run_agent_loop<TASK_RUNNER : ?Sized>(rx: &mut Receiver<OutputAgentMessage>, task_runner: &mut TASK_RUNNER)
+    /// Enter agent loop, executing tasks with given writer. Once no more tasks are queued, 
+    /// the writer is flushed. A flush error puts the agent in the failed state.
+    pub fn enter_flushing_agent_loop(self, msg_writer: &mut MessageWriter) {
+        let rx = self.rx;
+        loop {
+            let mut task_message = Self::receive(&rx);
+            
+            loop {
+                match task_message {
+                    OutputAgentMessage::Shutdown => { 
+                        Self::flush(msg_writer, &self.failure);
+                        return; 
+                    }
+                    OutputAgentMessage::Task(task, _) => {
+                        task(msg_writer);
+                    }
+                }
+                // Drain the queue before flushing
+                match rx.try_receive() {
+                    Some(next_message) => task_message = next_message,
+                    None => break,
+                }
+            }
+            
+            Self::flush(msg_writer, &self.failure);
+        }
+    }
+    
+    fn flush(msg_writer: &mut MessageWriter, failure: &FailureHandle) {
+        if let Err(error) = msg_writer.flush() {
+            error!("Error flushing JSON-RPC output: {}", error);
+            failure.fail(error);
+        }
+    }
+    
+    fn receive(rx: &TaskQueueReceiver) -> OutputAgentMessage {
+        // A task queue closed without explicit shutdown is treated as a shutdown
+        rx.receive().unwrap_or(OutputAgentMessage::Shutdown)
+    }
+    
+    pub fn run_agent_loop<TASK_RUNNER : ?Sized>(rx: &mut TaskQueueReceiver, task_runner: &mut TASK_RUNNER)
     where
          TASK_RUNNER : FnMut(OutputAgentTask) 
     {
         loop {
-            let task_message = rx.recv();
-            if let Err(err) = task_message {
-                // BM: Should we really panic if agent has not shutdown explicitly?
-                panic!("Error, task queue channel closed without explicit agent shutdown: {:?}", err);
-            }
-            let task_message = task_message.unwrap();
+            let task_message = Self::receive(rx);
             
             match task_message {
                 OutputAgentMessage::Shutdown => { 
                     return; 
                 }
-                OutputAgentMessage::Task(task) => {
+                OutputAgentMessage::Task(task, _) => {
                     task_runner(task);
                 }
             }
@@ -186,8 +765,89 @@ impl AgentInnerRunner {
 }
 
 
+/// The future that executes the tasks of an agent started with `OutputAgent::start_polled`.
+/// It completes once shutdown is requested and the queued tasks are executed.
+pub struct OutputDriver<OUT> {
+    rx : TaskQueueReceiver,
+    failure : FailureHandle,
+    msg_writer : OUT,
+    flush_when_idle : bool,
+}
+
+impl<OUT> OutputDriver<OUT> {
+    pub fn get_ref(&self) -> &OUT {
+        &self.msg_writer
+    }
+}
+
+impl<OUT : MessageWriter> OutputDriver<OUT> {
+    /// Flush if flushing when idle. This is done on every idle poll, even if nothing was written, 
+    /// so that a writer can complete a flush it couldn't before (see `SinkMessageWriter`).
+    fn flush_if_needed(&mut self) {
+        if self.flush_when_idle {
+            AgentInnerRunner::flush(&mut self.msg_writer, &self.failure);
+        }
+    }
+}
+
+impl<OUT : MessageWriter> Future for OutputDriver<OUT> {
+    type Item = ();
+    type Error = ();
+    
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            let task_message = match self.rx.poll_receive() {
+                // A task queue closed without explicit shutdown is treated as a shutdown
+                Async::Ready(task_message) => task_message.unwrap_or(OutputAgentMessage::Shutdown),
+                Async::NotReady => {
+                    self.flush_if_needed();
+                    return Ok(Async::NotReady);
+                }
+            };
+            
+            match task_message {
+                OutputAgentMessage::Shutdown => {
+                    self.flush_if_needed();
+                    return Ok(Async::Ready(()));
+                }
+                OutputAgentMessage::Task(task, _) => {
+                    task(&mut self.msg_writer);
+                }
+            }
+        }
+    }
+}
+
+
 /* -----------------  ----------------- */
 
+#[cfg(test)]
+pub mod test_util {
+    
+    use super::*;
+    use std::sync::mpsc;
+    use service_util::WriteLineMessageWriter;
+    
+    /// Start an agent that writes lines to the returned buffer. 
+    /// The agent only starts running tasks when the returned sender is sent something.
+    pub fn start_paused_agent(bound: TaskQueueBound) 
+        -> (OutputAgent, mpsc::Sender<()>, Arc<Mutex<Vec<u8>>>) 
+    {
+        let output = newArcMutex(vec![] as Vec<u8>);
+        let output2 = output.clone();
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        
+        let agent = OutputAgent::start_with_bound(move |inner_runner: AgentInnerRunner| {
+            resume_rx.recv().unwrap();
+            inner_runner.enter_agent_loop(&mut move |task: OutputAgentTask| {
+                task(&mut WriteLineMessageWriter(&mut *output2.lock().unwrap()));
+            });
+        }, bound);
+        (agent, resume_tx, output)
+    }
+    
+}
+
 #[test]
 fn test_OutputAgent() {
     
@@ -225,6 +885,209 @@ fn test_OutputAgent() {
     assert_equal(String::from_utf8(unwrap_ArcMutex(output)).unwrap(), "First response.\n".to_string());
 }
 
+#[test]
+fn test_OutputAgent_drop() {
+    
+    use util::tests::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use service_util::WriteLineMessageWriter;
+    
+    let output = newArcMutex(vec![] as Vec<u8>);
+    let output2 = output.clone();
+    let (done_tx, done_rx) = mpsc::channel::<()>();
+    
+    let agent = OutputAgent::start(move |inner_runner: AgentInnerRunner| {
+        inner_runner.enter_agent_loop(&mut |task: OutputAgentTask| {
+            task(&mut WriteLineMessageWriter(&mut *output2.lock().unwrap()));
+        });
+        done_tx.send(()).unwrap();
+    });
+    
+    agent.submit_task(new(|msg_writer| {
+        msg_writer.write_message("First response.").unwrap();
+    }));
+    // Dropping without explicit shutdown: queued tasks are still executed, then the agent stops.
+    drop(agent);
+    
+    done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let output = output.lock().unwrap().clone();
+    assert_equal(String::from_utf8(output).unwrap(), "First response.\n".to_string());
+}
+
+#[test]
+fn test_OutputAgent_bounded() {
+    
+    use util::tests::*;
+    use std::sync::mpsc;
+    use self::test_util::start_paused_agent;
+    
+    let start_agent = |when_full: QueueFullPolicy| {
+        start_paused_agent(TaskQueueBound::Bounded { capacity : 1, when_full : when_full })
+    };
+    let write_task = |message: &'static str| -> OutputAgentTask {
+        new(move |msg_writer| { msg_writer.write_message(message).unwrap(); })
+    };
+    // Submit from another thread, returning once that thread is about to submit
+    let spawn_submit = |agent: &OutputAgent, message: &'static str| {
+        let submitter = agent.task_submitter();
+        let (started_tx, started_rx) = mpsc::channel::<()>();
+        let submit_thread = thread::spawn(move || {
+            started_tx.send(()).unwrap();
+            submitter.try_submit_task_with_priority(write_task(message), TaskPriority::Normal)
+        });
+        started_rx.recv().unwrap();
+        submit_thread
+    };
+    
+    let (mut agent, resume_tx, output) = start_agent(QueueFullPolicy::Fail);
+    assert_eq!(agent.try_submit_task(write_task("1")), Ok(()));
+    assert_eq!(agent.try_submit_task(write_task("2")), Err(SubmitTaskError::QueueFull));
+    
+    resume_tx.send(()).unwrap();
+    agent.shutdown_and_join();
+    assert_equal(String::from_utf8(unwrap_ArcMutex(output)).unwrap(), "1\n".to_string());
+    
+    
+    let (mut agent, resume_tx, output) = start_agent(QueueFullPolicy::Block);
+    assert_eq!(agent.try_submit_task(write_task("1")), Ok(()));
+    // Blocks until the agent resumes
+    let submit_thread = spawn_submit(&agent, "2");
+    resume_tx.send(()).unwrap();
+    assert_eq!(submit_thread.join().unwrap(), Ok(()));
+    
+    agent.shutdown_and_join();
+    assert_equal(String::from_utf8(unwrap_ArcMutex(output)).unwrap(), "1\n2\n".to_string());
+    
+    
+    // A submitter blocked on a full queue is released by shutdown
+    let (mut agent, resume_tx, output) = start_agent(QueueFullPolicy::Block);
+    assert_eq!(agent.try_submit_task(write_task("1")), Ok(()));
+    let submit_thread = spawn_submit(&agent, "2");
+    // The agent is still paused, so only the shutdown request can release the submitter
+    agent.request_shutdown();
+    assert_eq!(submit_thread.join().unwrap(), Err(SubmitTaskError::Shutdown));
+    
+    resume_tx.send(()).unwrap();
+    agent.shutdown_and_join();
+    assert_equal(String::from_utf8(unwrap_ArcMutex(output)).unwrap(), "1\n".to_string());
+}
+
+#[test]
+fn test_OutputAgent_priority() {
+    
+    use util::tests::*;
+    use self::test_util::start_paused_agent;
+    
+    let (mut agent, resume_tx, output) = start_paused_agent(TaskQueueBound::Unbounded);
+    
+    let submit = |message: &'static str, priority: TaskPriority| {
+        let task : OutputAgentTask = new(move |msg_writer| { msg_writer.write_message(message).unwrap(); });
+        agent.try_submit_task_with_priority(task, priority).unwrap();
+    };
+    submit("low 1", TaskPriority::Low);
+    submit("normal 1", TaskPriority::Normal);
+    submit("low 2", TaskPriority::Low);
+    submit("high 1", TaskPriority::High);
+    submit("normal 2", TaskPriority::Normal);
+    submit("high 2", TaskPriority::High);
+    
+    agent.request_shutdown();
+    resume_tx.send(()).unwrap();
+    agent.shutdown_and_join();
+    
+    assert_equal(String::from_utf8(unwrap_ArcMutex(output)).unwrap(), 
+        "high 1\nhigh 2\nnormal 1\nnormal 2\nlow 1\nlow 2\n".to_string());
+}
+
+#[test]
+fn test_OutputAgent_polled() {
+    
+    use util::tests::*;
+    use std::sync::mpsc;
+    use service_util::WriteLineMessageWriter;
+    
+    let write_task = |message: &'static str| -> OutputAgentTask {
+        new(move |msg_writer| { msg_writer.write_message(message).unwrap(); })
+    };
+    
+    // Driver polled by the submitting thread
+    let (mut agent, mut driver) = 
+        OutputAgent::start_polled(WriteLineMessageWriter(vec![]), OutputAgentOptions::default());
+    agent.submit_task(write_task("1"));
+    agent.submit_task(write_task("2"));
+    agent.shutdown_and_join();
+    (&mut driver).wait().unwrap();
+    assert_equal(String::from_utf8(driver.get_ref().0.clone()).unwrap(), "1\n2\n".to_string());
+    
+    // Driver waiting for tasks in another thread
+    let (mut agent, driver) = 
+        OutputAgent::start_polled(WriteLineMessageWriter(vec![]), OutputAgentOptions::default());
+    let (started_tx, started_rx) = mpsc::channel::<()>();
+    let driver_thread = thread::spawn(move || {
+        let mut driver = driver;
+        started_tx.send(()).unwrap();
+        (&mut driver).wait().unwrap();
+        driver.get_ref().0.clone()
+    });
+    started_rx.recv().unwrap();
+    agent.submit_task(write_task("1"));
+    agent.submit_task(write_task("2"));
+    agent.shutdown_and_join();
+    
+    let output = driver_thread.join().unwrap();
+    assert_equal(String::from_utf8(output).unwrap(), "1\n2\n".to_string());
+}
+
+#[test]
+fn test_OutputAgent_flush_when_idle() {
+    
+    use util::tests::*;
+    use std::sync::mpsc;
+    use service_util::GError;
+    
+    /// Records writes and flushes
+    struct RecordingWriter(Arc<Mutex<Vec<String>>>);
+    
+    impl MessageWriter for RecordingWriter {
+        fn write_message(&mut self, msg: &str) -> Result<(), GError> {
+            self.0.lock().unwrap().push(msg.to_string());
+            Ok(())
+        }
+        
+        fn flush(&mut self) -> Result<(), GError> {
+            self.0.lock().unwrap().push("<flush>".to_string());
+            Ok(())
+        }
+    }
+    
+    let run = |flush_when_idle: bool| {
+        let log = newArcMutex(vec![]);
+        let log2 = log.clone();
+        // The agent only starts running tasks when `resume_tx` is sent something
+        let (resume_tx, resume_rx) = mpsc::channel::<()>();
+        
+        let options = OutputAgentOptions { flush_when_idle : flush_when_idle, .. OutputAgentOptions::default() };
+        let mut agent = OutputAgent::start_with_provider_and_options(move || {
+            resume_rx.recv().unwrap();
+            RecordingWriter(log2)
+        }, options);
+        
+        for message in &["1", "2", "3"] {
+            agent.submit_task(new(move |msg_writer| { msg_writer.write_message(message).unwrap(); }));
+        }
+        agent.request_shutdown();
+        resume_tx.send(()).unwrap();
+        agent.shutdown_and_join();
+        
+        let log = log.lock().unwrap().clone();
+        log
+    };
+    
+    assert_equal(run(false), vec!["1".to_string(), "2".into(), "3".into()]);
+    assert_equal(run(true), vec!["1".to_string(), "2".into(), "3".into(), "<flush>".into()]);
+}
+
 // The following code we don't want to run, we just want to test that it compiles
 #[cfg(test)]
 pub fn test_OutputAgent_API() {
@@ -6,30 +6,134 @@
 // except according to those terms.
 
 use std::result::Result;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
 use std::io;
+use std::str;
+use std::time::Duration;
+use std::time::Instant;
+
+use futures::AsyncSink;
+use futures::Sink;
 
 pub use util::core::GError;
 pub use util::core::GResult;
 
+use message_codec::MessageLimits;
+use message_codec::DEFAULT_MAX_MESSAGE_SIZE;
+
 
 pub trait MessageReader {
     fn read_next(&mut self) -> Result<String, GError>;
+    
+    /// Read the next message as raw bytes. Binary transports should override this.
+    fn read_next_bytes(&mut self) -> Result<Vec<u8>, GError> {
+        Ok(try!(self.read_next()).into_bytes())
+    }
+    
+    /// Apply given limits to the messages read from now on. Readers that buffer a message before 
+    /// it is decoded should override this, to enforce `max_message_size` while reading.
+    /// 
+    /// The read loops of `EndpointHandler` call this with the Endpoint's limits.
+    fn apply_limits(&mut self, _limits: &MessageLimits) {
+    }
+}
+
+/// The error of a MessageReader that reached the end of its input, between messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndOfInput;
+
+impl fmt::Display for EndOfInput {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("End of input stream.")
+    }
+}
+
+impl error::Error for EndOfInput {
+}
+
+/// Whether given read error is `EndOfInput`.
+pub fn is_end_of_input(error: &GError) -> bool {
+    error.downcast_ref::<EndOfInput>().is_some()
 }
 
 /// Read a message by reading lines from a BufRead.
+/// A line exceeding `DEFAULT_MAX_MESSAGE_SIZE` bytes (not counting the newline) is an error, 
+/// and is skipped without being buffered: the next read continues with the next line. 
+/// Other limits are not applied while reading (the Endpoint applies them when decoding).
+/// End of input is reported as an `EndOfInput` error.
 /// This is of use mainly for tests and example code.
 pub struct ReadLineMessageReader<T: io::BufRead>(pub T);
 
 impl<T : io::BufRead> MessageReader for ReadLineMessageReader<T> {
     fn read_next(&mut self) -> Result<String, GError> {
-        let mut result = String::new();
-        try!(self.0.read_line(&mut result));
-        Ok(result)
+        let bytes = try!(self.read_next_bytes());
+        Ok(try!(String::from_utf8(bytes)))
+    }
+    
+    fn read_next_bytes(&mut self) -> Result<Vec<u8>, GError> {
+        let line = try!(read_line_bytes(&mut self.0, DEFAULT_MAX_MESSAGE_SIZE));
+        if line.is_empty() {
+            return Err(EndOfInput.into());
+        }
+        Ok(line)
+    }
+}
+
+/// Read a line, including the newline, failing if it exceeds `max_size` bytes (not counting the newline).
+/// An oversized line is not buffered, but it is consumed up to its newline, 
+/// so that the next read starts at the next line.
+/// At end of input, the remaining bytes are returned, which is empty if there are none.
+fn read_line_bytes<T : io::BufRead>(input: &mut T, max_size: usize) -> Result<Vec<u8>, GError> {
+    let mut result = vec![];
+    let mut oversized = false;
+    loop {
+        let (found_newline, used) = {
+            let available = try!(input.fill_buf());
+            if available.is_empty() {
+                break;
+            }
+            let (found_newline, used) = match available.iter().position(|byte| *byte == b'\n') {
+                Some(index) => (true, index + 1),
+                None => (false, available.len()),
+            };
+            if !oversized {
+                result.extend_from_slice(&available[..used]);
+                let content_len = if found_newline { result.len() - 1 } else { result.len() };
+                if content_len > max_size {
+                    oversized = true;
+                    result = vec![];
+                }
+            }
+            (found_newline, used)
+        };
+        input.consume(used);
+        
+        if found_newline {
+            break;
+        }
+    }
+    
+    if oversized {
+        return Err(format!("Message exceeds maximum size of {} bytes.", max_size).into());
     }
+    Ok(result)
 }
 
 pub trait MessageWriter {
     fn write_message(&mut self, msg: &str) -> Result<(), GError>;
+    
+    /// Write a message given as raw bytes. Binary transports should override this.
+    fn write_message_bytes(&mut self, msg: &[u8]) -> Result<(), GError> {
+        let msg = try!(str::from_utf8(msg));
+        self.write_message(msg)
+    }
+    
+    /// Flush messages written but not yet sent. Buffered writers should override this.
+    fn flush(&mut self) -> Result<(), GError> {
+        Ok(())
+    }
 }
 
 /// Handle a message simply by writing to a io::Write and appending a newline.
@@ -43,4 +147,258 @@ impl<T : io::Write> MessageWriter for WriteLineMessageWriter<T> {
         try!(self.0.flush());
         Ok(())
     }
+}
+
+/// Write messages as lines, like `WriteLineMessageWriter`, but buffered: the output is flushed only 
+/// on `flush`, or once a message is written when the oldest unflushed message is older than `max_latency`.
+/// 
+/// Meant for an OutputAgent that flushes when idle (see `OutputAgentOptions`), 
+/// so that messages are not held back once there are no more to write.
+pub struct BufferedWriteLineMessageWriter<T: io::Write> {
+    output : io::BufWriter<T>,
+    max_latency : Duration,
+    unflushed_since : Option<Instant>,
+}
+
+impl<T : io::Write> BufferedWriteLineMessageWriter<T> {
+    pub fn new(output: T, max_latency: Duration) -> Self {
+        BufferedWriteLineMessageWriter { 
+            output : io::BufWriter::new(output), max_latency : max_latency, unflushed_since : None 
+        }
+    }
+    
+    /// The underlying output. Only flushed messages have been written to it.
+    pub fn get_ref(&self) -> &T {
+        self.output.get_ref()
+    }
+}
+
+impl<T : io::Write> MessageWriter for BufferedWriteLineMessageWriter<T> {
+    fn write_message(&mut self, msg: &str) -> Result<(), GError> {
+        try!(io::Write::write_all(&mut self.output, msg.as_bytes()));
+        try!(io::Write::write_all(&mut self.output, &['\n' as u8]));
+        
+        let unflushed_since = *self.unflushed_since.get_or_insert_with(Instant::now);
+        if unflushed_since.elapsed() >= self.max_latency {
+            try!(MessageWriter::flush(self));
+        }
+        Ok(())
+    }
+    
+    fn flush(&mut self) -> Result<(), GError> {
+        self.unflushed_since = None;
+        try!(io::Write::flush(&mut self.output));
+        Ok(())
+    }
+}
+
+/// Read messages framed with a 4 byte, big-endian, length prefix.
+/// This framing is suitable for binary codecs (see `message_codec`).
+/// 
+/// A length prefix greater than `max_size` is an error, and no message data is read. 
+/// By default, `max_size` is the default `MessageLimits::max_message_size`.
+pub struct LengthPrefixedMessageReader<T: io::Read> {
+    pub input : T,
+    pub max_size : usize,
+}
+
+impl<T : io::Read> LengthPrefixedMessageReader<T> {
+    pub fn new(input: T) -> Self {
+        Self::with_limits(input, &MessageLimits::default())
+    }
+    
+    pub fn with_limits(input: T, limits: &MessageLimits) -> Self {
+        Self::new_bounded(input, limits.max_message_size)
+    }
+    
+    pub fn new_bounded(input: T, max_size: usize) -> Self {
+        LengthPrefixedMessageReader { input : input, max_size : max_size }
+    }
+}
+
+impl<T : io::Read> MessageReader for LengthPrefixedMessageReader<T> {
+    fn read_next(&mut self) -> Result<String, GError> {
+        let bytes = try!(self.read_next_bytes());
+        Ok(try!(String::from_utf8(bytes)))
+    }
+    
+    fn read_next_bytes(&mut self) -> Result<Vec<u8>, GError> {
+        let mut len_bytes = [0u8; 4];
+        // End of input before a length prefix is the end of the messages, anywhere else it's an error
+        let first_read = loop {
+            match self.input.read(&mut len_bytes[..1]) {
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                first_read => break first_read,
+            }
+        };
+        if try!(first_read) == 0 {
+            return Err(EndOfInput.into());
+        }
+        try!(self.input.read_exact(&mut len_bytes[1..]));
+        let len = len_bytes.iter().fold(0usize, |acc, byte| (acc << 8) | (*byte as usize));
+        
+        if len > self.max_size {
+            return Err(format!("Message length {} exceeds maximum size of {} bytes.", len, self.max_size).into());
+        }
+        
+        let mut msg = vec![0u8; len];
+        try!(self.input.read_exact(&mut msg));
+        Ok(msg)
+    }
+    
+    fn apply_limits(&mut self, limits: &MessageLimits) {
+        self.max_size = limits.max_message_size;
+    }
+}
+
+/// Write messages framed with a 4 byte, big-endian, length prefix.
+pub struct LengthPrefixedMessageWriter<T: io::Write>(pub T);
+
+impl<T : io::Write> MessageWriter for LengthPrefixedMessageWriter<T> {
+    fn write_message(&mut self, msg: &str) -> Result<(), GError> {
+        self.write_message_bytes(msg.as_bytes())
+    }
+    
+    fn write_message_bytes(&mut self, msg: &[u8]) -> Result<(), GError> {
+        if msg.len() > u32::max_value() as usize {
+            return Err(format!("Message length {} exceeds length prefix range.", msg.len()).into());
+        }
+        let len = msg.len() as u32;
+        try!(self.0.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]));
+        try!(self.0.write_all(msg));
+        try!(self.0.flush());
+        Ok(())
+    }
+}
+
+/* -----------------  futures adapters  ----------------- */
+
+/// A MessageWriter that sends messages to a `Sink`. Messages the sink is not ready to accept are 
+/// kept, and sent on a later write or flush.
+/// 
+/// The sink is used without blocking, so writing must happen in a futures task, which the sink 
+/// notifies once it's ready: this is meant for an OutputAgent started with `OutputAgent::start_polled`, 
+/// and flushing when idle (see `OutputAgentOptions`).
+pub struct SinkMessageWriter<S> {
+    sink : S,
+    unsent : VecDeque<String>,
+}
+
+impl<S> SinkMessageWriter<S> {
+    pub fn new(sink: S) -> Self {
+        SinkMessageWriter { sink : sink, unsent : VecDeque::new() }
+    }
+    
+    pub fn get_ref(&self) -> &S {
+        &self.sink
+    }
+}
+
+impl<S : Sink<SinkItem=String>> SinkMessageWriter<S> 
+where 
+    S::SinkError : Into<GError>,
+{
+    fn send_unsent(&mut self) -> Result<(), GError> {
+        while let Some(msg) = self.unsent.pop_front() {
+            match self.sink.start_send(msg) {
+                Ok(AsyncSink::Ready) => {}
+                Ok(AsyncSink::NotReady(msg)) => {
+                    self.unsent.push_front(msg);
+                    break;
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S : Sink<SinkItem=String>> MessageWriter for SinkMessageWriter<S> 
+where 
+    S::SinkError : Into<GError>,
+{
+    fn write_message(&mut self, msg: &str) -> Result<(), GError> {
+        self.unsent.push_back(msg.to_string());
+        self.send_unsent()
+    }
+    
+    fn flush(&mut self) -> Result<(), GError> {
+        try!(self.send_unsent());
+        try!(self.sink.poll_complete().map_err(Into::into));
+        Ok(())
+    }
+}
+
+/* -----------------  ----------------- */
+
+#[test]
+fn test_LengthPrefixedMessage() {
+    use util::tests::*;
+    
+    let mut output = vec![];
+    {
+        let mut writer = LengthPrefixedMessageWriter(&mut output);
+        writer.write_message_bytes(&[0xc0, 0xff]).unwrap();
+        writer.write_message("abc").unwrap();
+    }
+    assert_equal(output.clone(), vec![0, 0, 0, 2, 0xc0, 0xff, 0, 0, 0, 3, b'a', b'b', b'c']);
+    
+    let mut reader = LengthPrefixedMessageReader::new(&output[..]);
+    assert_equal(reader.read_next_bytes().unwrap(), vec![0xc0, 0xff]);
+    assert_equal(reader.read_next().unwrap(), "abc".to_string());
+    assert!(reader.read_next_bytes().is_err());
+    
+    // A hostile length prefix is rejected by default, without allocating
+    let mut reader = LengthPrefixedMessageReader::new(&[0xff, 0xff, 0xff, 0xff, 0x00][..]);
+    check_err_contains(reader.read_next_bytes().unwrap_err(), "Message length 4294967295 exceeds maximum size");
+    
+    let mut reader = LengthPrefixedMessageReader::new_bounded(&output[..], 2);
+    assert_equal(reader.read_next_bytes().unwrap(), vec![0xc0, 0xff]);
+    check_err_contains(reader.read_next_bytes().unwrap_err(), "Message length 3 exceeds maximum size of 2");
+}
+
+#[test]
+fn test_BufferedWriteLineMessageWriter() {
+    use util::tests::*;
+    
+    let mut writer = BufferedWriteLineMessageWriter::new(vec![], Duration::from_secs(3600));
+    writer.write_message("abc").unwrap();
+    writer.write_message("def").unwrap();
+    assert_equal(writer.get_ref().clone(), vec![]);
+    MessageWriter::flush(&mut writer).unwrap();
+    assert_equal(writer.get_ref().clone(), b"abc\ndef\n".to_vec());
+    
+    // With no latency allowed, every message is flushed
+    let mut writer = BufferedWriteLineMessageWriter::new(vec![], Duration::from_secs(0));
+    writer.write_message("abc").unwrap();
+    assert_equal(writer.get_ref().clone(), b"abc\n".to_vec());
+}
+
+#[test]
+fn test_ReadLineMessageReader() {
+    use util::tests::*;
+    
+    let input = "abc\n{}\n123456\n";
+    let mut reader = ReadLineMessageReader(io::BufReader::with_capacity(2, input.as_bytes()));
+    assert_equal(reader.read_next().unwrap(), "abc\n".to_string());
+    assert_equal(reader.read_next().unwrap(), "{}\n".to_string());
+    assert_equal(reader.read_next().unwrap(), "123456\n".to_string());
+    check_err_contains(reader.read_next().unwrap_err(), "End of input stream");
+    
+    let input = "x".repeat(DEFAULT_MAX_MESSAGE_SIZE + 1) + "\nabc\n";
+    let mut reader = ReadLineMessageReader(input.as_bytes());
+    check_err_contains(reader.read_next().unwrap_err(), 
+        &format!("Message exceeds maximum size of {} bytes", DEFAULT_MAX_MESSAGE_SIZE));
+    // Reading continues with the line after the oversized one
+    assert_equal(reader.read_next().unwrap(), "abc\n".to_string());
+    check_err_contains(reader.read_next().unwrap_err(), "End of input stream");
+    
+    // The newline doesn't count towards the size
+    let input = "abcd\n123456\nxyz\n12345";
+    let mut input = io::BufReader::with_capacity(2, input.as_bytes());
+    assert_equal(read_line_bytes(&mut input, 4).unwrap(), b"abcd\n".to_vec());
+    check_err_contains(read_line_bytes(&mut input, 4).unwrap_err(), "Message exceeds maximum size of 4 bytes");
+    assert_equal(read_line_bytes(&mut input, 4).unwrap(), b"xyz\n".to_vec());
+    check_err_contains(read_line_bytes(&mut input, 4).unwrap_err(), "Message exceeds maximum size of 4 bytes");
+    assert_equal(read_line_bytes(&mut input, 4).unwrap(), vec![]);
 }
\ No newline at end of file
@@ -11,6 +11,8 @@ pub struct Point {
     pub y: i32,
 }
 
+json_schema_struct!(Point { x : i32, y : i32 });
+
 pub fn new_sample_params(x: i32, y: i32) -> Point {
     Point { x : x, y : y }
 }
@@ -22,6 +24,17 @@ fn test_Point() {
     test_serde(&Point{ x: 12, y : 34});
 }
 
+#[test]
+fn test_Point_schema() {
+    use json_schema::{JsonSchema, validate};
+    use serde_json;
+    
+    // Check the schema declared with json_schema_struct! matches the serialized form
+    let value = serde_json::to_value(&Point{ x: 12, y : -34});
+    assert_eq!(validate(&Point::json_schema(), &value), vec![]);
+    assert!(!validate(&Point::json_schema(), &serde_json::to_value(&(12, -34))).is_empty());
+}
+
 pub enum PointField {
     X,
     Y,
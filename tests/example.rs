@@ -15,7 +15,7 @@ and then has the client invoke method `my_method` and await the result.
 
 */
 
-extern crate jsonrpc;
+#[macro_use] extern crate jsonrpc;
 extern crate futures;
 extern crate serde;
 
@@ -27,6 +27,7 @@ mod tests_sample_types;
 use jsonrpc::method_types::MethodResult;
 use jsonrpc::EndpointHandler;
 use jsonrpc::Endpoint;
+use jsonrpc::EndpointOptions;
 use jsonrpc::RequestFuture;
 use jsonrpc::NullRequestHandler;
 use jsonrpc::map_request_handler::MapRequestHandler;
@@ -40,6 +41,9 @@ use futures::Future;
 
 use tests_sample_types::Point;
 
+// Describe Point for `rpc.discover`
+json_schema_struct!(Point { x : i32, y : i32 });
+
 use log::LogLevelFilter;
 use env_logger::LogBuilder;
 
@@ -67,7 +71,9 @@ pub fn test_client_server_communication() {
     
     let msg_writer = WriteLineMessageWriter(stream.try_clone().expect("Failed to clone stream"));
     let output_agent = OutputAgent::start_with_provider(|| msg_writer);
-    let mut endpoint = Endpoint::start_with(output_agent);
+    // Check the server messages strictly conform to the spec
+    let options = EndpointOptions { strict_validation : true, .. EndpointOptions::default() };
+    let mut endpoint = Endpoint::start_with_options(output_agent, options);
     
     let endpoint2 = endpoint.clone();
     // Create a thread to handle the client endpoint
@@ -107,7 +113,7 @@ fn run_server_listener(listener: TcpListener) {
 
 fn handle_server_connection(stream: TcpStream) {
     let mut request_handler = MapRequestHandler::new();
-    request_handler.add_request("my_method", Box::new(my_method));
+    request_handler.add_request_with_schema("my_method", Box::new(my_method)).unwrap();
     
     let msg_writer = WriteLineMessageWriter(stream.try_clone().expect("Failed to clone stream"));
     let endpoint = EndpointHandler::create_with_writer(msg_writer, Box::new(request_handler));